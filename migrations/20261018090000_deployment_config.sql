@@ -0,0 +1,2 @@
+ALTER TABLE deployments
+    ADD COLUMN config TEXT; -- content of prezel.json, null until the deployment is built
@@ -0,0 +1,4 @@
+ALTER TABLE projects
+    ADD COLUMN basic_auth_username TEXT; -- null = basic auth disabled
+ALTER TABLE projects
+    ADD COLUMN basic_auth_password TEXT; -- pbkdf2 hash
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS ip_rules (
+    id INTEGER PRIMARY KEY NOT NULL,
+    cidr TEXT NOT NULL,
+    action TEXT NOT NULL, -- allow | deny
+    environment TEXT, -- production | preview, null = all environments
+    project TEXT NOT NULL,
+    FOREIGN KEY (project) REFERENCES projects(id) ON DELETE CASCADE
+);
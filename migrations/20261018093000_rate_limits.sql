@@ -0,0 +1,2 @@
+ALTER TABLE projects
+    ADD COLUMN rate_limits TEXT; -- json, null = no rate limits
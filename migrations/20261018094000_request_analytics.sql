@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS request_analytics (
+    deployment TEXT NOT NULL, -- not a foreign key so the analytics survive deleted deployments
+    start INTEGER NOT NULL, -- start of the bucket, epoch in seconds
+    requests INTEGER NOT NULL,
+    status_1xx INTEGER NOT NULL,
+    status_2xx INTEGER NOT NULL,
+    status_3xx INTEGER NOT NULL,
+    status_4xx INTEGER NOT NULL,
+    status_5xx INTEGER NOT NULL,
+    latency TEXT NOT NULL, -- json array with the counts of the latency histogram
+    paths TEXT NOT NULL, -- json object with the request count of the top paths
+    referrers TEXT NOT NULL, -- json object with the request count of the top referrer hosts
+    project TEXT NOT NULL,
+    FOREIGN KEY (project) REFERENCES projects(id) ON DELETE CASCADE,
+    PRIMARY KEY (deployment, start)
+);
+
+CREATE INDEX IF NOT EXISTS request_analytics_project ON request_analytics(project, start);
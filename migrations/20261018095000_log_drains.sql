@@ -0,0 +1,2 @@
+ALTER TABLE projects
+    ADD COLUMN log_drains TEXT; -- json array, null = no drains
@@ -0,0 +1,18 @@
+ALTER TABLE projects
+    ADD COLUMN notification_channels TEXT; -- json array, null = no channels
+
+CREATE TABLE IF NOT EXISTS notification_deliveries (
+    id INTEGER PRIMARY KEY NOT NULL,
+    event TEXT NOT NULL,
+    deployment TEXT NOT NULL, -- not a foreign key so the deliveries survive deleted deployments
+    channel TEXT NOT NULL, -- webhook | slack | discord
+    url TEXT NOT NULL,
+    attempt INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    status INTEGER, -- http status of the response, null if no response was received
+    error TEXT, -- null if the notification was delivered
+    project TEXT NOT NULL,
+    FOREIGN KEY (project) REFERENCES projects(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS notification_deliveries_project ON notification_deliveries(project, timestamp);
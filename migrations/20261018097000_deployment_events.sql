@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS deployment_events (
+    id INTEGER PRIMARY KEY NOT NULL,
+    event TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    detail TEXT, -- extra information such as the exit code of a crashed container
+    deployment TEXT NOT NULL,
+    FOREIGN KEY (deployment) REFERENCES deployments(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS deployment_events_deployment ON deployment_events(deployment, timestamp);
@@ -0,0 +1,4 @@
+ALTER TABLE deployments
+    ADD COLUMN image_id TEXT; -- docker image of the last successful build
+ALTER TABLE deployments
+    ADD COLUMN image_digest TEXT; -- repo digest of the image, null for images only available locally
@@ -0,0 +1,2 @@
+ALTER TABLE projects
+    ADD COLUMN build_timeout INTEGER; -- in seconds, null = default timeout
@@ -0,0 +1,2 @@
+ALTER TABLE projects
+    ADD COLUMN cancel_superseded_builds INTEGER NOT NULL DEFAULT 0; -- 0 false 1 true, also stop running builds of superseded deployments
@@ -0,0 +1,2 @@
+ALTER TABLE deployments
+    ADD COLUMN no_cache INTEGER NOT NULL DEFAULT 0; -- 0 false 1 true, build without reusing previous builds
@@ -0,0 +1,2 @@
+ALTER TABLE env ADD COLUMN scope TEXT NOT NULL DEFAULT 'both'; -- build | runtime | both
+ALTER TABLE deployment_env ADD COLUMN scope TEXT NOT NULL DEFAULT 'both';
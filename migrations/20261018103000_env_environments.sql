@@ -0,0 +1,19 @@
+-- the primary key can't be changed in place, so the table is recreated
+CREATE TABLE env_new (
+    name TEXT NOT NULL,
+    value TEXT NOT NULL,
+    edited INTEGER NOT NULL,
+    scope TEXT NOT NULL DEFAULT 'both', -- build | runtime | both
+    environment TEXT, -- production | preview, null = all environments
+    branch TEXT, -- branch name or pattern using *, only for previews
+    project TEXT NOT NULL,
+    FOREIGN KEY (project) REFERENCES projects(id) ON DELETE CASCADE
+);
+
+INSERT INTO env_new (name, value, edited, scope, project)
+SELECT name, value, edited, scope, project FROM env;
+
+DROP TABLE env;
+ALTER TABLE env_new RENAME TO env;
+
+CREATE UNIQUE INDEX env_target ON env (project, name, ifnull(environment, ''), ifnull(branch, ''));
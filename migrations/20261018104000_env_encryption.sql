@@ -0,0 +1,10 @@
+-- the data key used to encrypt env values, wrapped with a key derived from the instance secret
+CREATE TABLE IF NOT EXISTS encryption_keys (
+    id INTEGER PRIMARY KEY,
+    wrapped_key TEXT NOT NULL,
+    created INTEGER NOT NULL
+);
+
+-- existing values are encrypted on startup, as that can't be done from sql
+ALTER TABLE env ADD COLUMN encrypted INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE deployment_env ADD COLUMN encrypted INTEGER NOT NULL DEFAULT 0;
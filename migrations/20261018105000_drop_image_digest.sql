@@ -0,0 +1,2 @@
+ALTER TABLE deployments
+    DROP COLUMN image_digest; -- images are never pushed, so there was never a repo digest to store
@@ -0,0 +1,13 @@
+-- the deployment serving production the last time it changed, so restarts don't notify again
+ALTER TABLE projects ADD COLUMN promoted_deployment TEXT;
+
+-- existing apps start from their current production deployment instead of notifying on upgrade
+UPDATE projects SET promoted_deployment = (
+    SELECT id FROM deployments
+    WHERE deployments.project = projects.id
+        AND default_branch = 1
+        AND result = 'built'
+        AND deleted IS NULL
+    ORDER BY created DESC
+    LIMIT 1
+);
@@ -0,0 +1,362 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use url::Url;
+use utoipa::ToSchema;
+
+use crate::{
+    db::{nano_id::NanoId, Db},
+    logging::RequestLog,
+    utils::now_in_seconds,
+};
+
+/// request logs are rolled up into buckets of this many seconds
+pub(crate) const BUCKET_SECONDS: i64 = 5 * 60;
+/// buckets older than this are removed from the db
+const RETENTION_SECONDS: i64 = 30 * 24 * 60 * 60;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// upper bounds in milliseconds of the latency histogram, the last slot counts everything above
+const LATENCY_BOUNDS: [i64; 10] = [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+/// max number of distinct paths or referrers tracked per bucket
+const MAX_KEYS: usize = 1000;
+/// number of paths or referrers stored per bucket and returned by the API
+const TOP_ENTRIES: usize = 10;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub(crate) struct BucketStats {
+    pub(crate) requests: i64,
+    /// number of responses for each status class, from 1xx to 5xx
+    pub(crate) status: [i64; 5],
+    pub(crate) latency: Vec<i64>,
+    pub(crate) paths: HashMap<String, i64>,
+    pub(crate) referrers: HashMap<String, i64>,
+}
+
+impl BucketStats {
+    fn record(&mut self, log: &RequestLog) {
+        self.requests += 1;
+        let class = (log.status as usize / 100).checked_sub(1);
+        if let Some(count) = class.and_then(|class| self.status.get_mut(class)) {
+            *count += 1;
+        }
+        self.latency.resize(LATENCY_BOUNDS.len() + 1, 0);
+        let slot = LATENCY_BOUNDS
+            .iter()
+            .position(|bound| log.duration as i64 <= *bound)
+            .unwrap_or(LATENCY_BOUNDS.len());
+        self.latency[slot] += 1;
+        increment(&mut self.paths, &log.path, 1);
+        if let Some(referrer) = get_referrer_host(log) {
+            increment(&mut self.referrers, &referrer, 1);
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: &BucketStats) {
+        self.requests += other.requests;
+        for (count, other) in self.status.iter_mut().zip(other.status) {
+            *count += other;
+        }
+        if self.latency.len() < other.latency.len() {
+            self.latency.resize(other.latency.len(), 0);
+        }
+        for (count, other) in self.latency.iter_mut().zip(&other.latency) {
+            *count += other;
+        }
+        for (path, count) in &other.paths {
+            increment(&mut self.paths, path, *count);
+        }
+        for (referrer, count) in &other.referrers {
+            increment(&mut self.referrers, referrer, *count);
+        }
+    }
+
+    /// only keeps the most requested paths and referrers, used before storing the bucket
+    pub(crate) fn truncate(&mut self) {
+        self.paths = top_entries(&self.paths, TOP_ENTRIES).into_iter().collect();
+        self.referrers = top_entries(&self.referrers, TOP_ENTRIES)
+            .into_iter()
+            .collect();
+    }
+
+    /// upper bound in milliseconds of the histogram slot containing the given percentile
+    fn percentile(&self, percentile: f64) -> Option<i64> {
+        let total: i64 = self.latency.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let target = (total as f64 * percentile).ceil() as i64;
+        let mut accumulated = 0;
+        for (slot, count) in self.latency.iter().enumerate() {
+            accumulated += count;
+            if accumulated >= target {
+                let last = LATENCY_BOUNDS.len() - 1;
+                return Some(LATENCY_BOUNDS[slot.min(last)]);
+            }
+        }
+        None
+    }
+}
+
+fn increment(map: &mut HashMap<String, i64>, key: &str, count: i64) {
+    if let Some(current) = map.get_mut(key) {
+        *current += count;
+    } else if map.len() < MAX_KEYS {
+        map.insert(key.to_owned(), count);
+    }
+}
+
+fn top_entries(map: &HashMap<String, i64>, limit: usize) -> Vec<(String, i64)> {
+    let mut entries: Vec<_> = map
+        .iter()
+        .map(|(key, count)| (key.clone(), *count))
+        .collect();
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    entries.truncate(limit);
+    entries
+}
+
+/// referrers are aggregated by host, ignoring navigation within the same host
+fn get_referrer_host(log: &RequestLog) -> Option<String> {
+    let referrer = Url::parse(log.referer.as_ref()?).ok()?;
+    let host = referrer.host_str()?;
+    (host != log.host).then(|| host.to_owned())
+}
+
+/// Rolls request logs up in memory and periodically merges them into the db
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Analytics {
+    pending: Arc<Mutex<HashMap<(NanoId, i64), BucketStats>>>,
+}
+
+impl Analytics {
+    pub(crate) fn record(&self, log: &RequestLog) {
+        let start = log.time / 1000 / BUCKET_SECONDS * BUCKET_SECONDS;
+        let mut pending = self.pending.lock().unwrap();
+        pending
+            .entry((log.deployment.clone(), start))
+            .or_default()
+            .record(log);
+    }
+
+    pub(crate) fn start_flushing(&self, db: Db) {
+        let analytics = self.clone();
+        tokio::spawn(async move {
+            let mut projects = HashMap::new();
+            let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+            loop {
+                interval.tick().await;
+                analytics.flush(&db, &mut projects).await;
+            }
+        });
+    }
+
+    async fn flush(&self, db: &Db, projects: &mut HashMap<NanoId, NanoId>) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for ((deployment, start), stats) in pending {
+            let project = match projects.get(&deployment) {
+                Some(project) => project.clone(),
+                None => match db.get_deployment(&deployment).await {
+                    Some(info) => {
+                        projects.insert(deployment.clone(), info.project.clone());
+                        info.project
+                    }
+                    None => {
+                        error!("dropping analytics for unknown deployment {deployment}");
+                        continue;
+                    }
+                },
+            };
+            db.merge_analytics_bucket(&project, &deployment, start, &stats)
+                .await;
+        }
+        db.delete_analytics_before(now_in_seconds() - RETENTION_SECONDS)
+            .await;
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, ToSchema)]
+pub(crate) enum AnalyticsRange {
+    #[serde(rename = "1h")]
+    Hour,
+    #[serde(rename = "24h")]
+    #[default]
+    Day,
+    #[serde(rename = "7d")]
+    Week,
+    #[serde(rename = "30d")]
+    Month,
+}
+
+impl AnalyticsRange {
+    pub(crate) fn seconds(&self) -> i64 {
+        match self {
+            Self::Hour => 60 * 60,
+            Self::Day => 24 * 60 * 60,
+            Self::Week => 7 * 24 * 60 * 60,
+            Self::Month => 30 * 24 * 60 * 60,
+        }
+    }
+
+    /// seconds covered by each point of the series
+    fn step(&self) -> i64 {
+        match self {
+            Self::Hour => BUCKET_SECONDS,
+            Self::Day => 60 * 60,
+            Self::Week => 6 * 60 * 60,
+            Self::Month => 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub(crate) struct AnalyticsPoint {
+    /// start of the step, epoch in seconds
+    time: i64,
+    requests: i64,
+    status_1xx: i64,
+    status_2xx: i64,
+    status_3xx: i64,
+    status_4xx: i64,
+    status_5xx: i64,
+    /// milliseconds, approximated to the bounds of a fixed histogram
+    p50: Option<i64>,
+    p95: Option<i64>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub(crate) struct TopEntry {
+    value: String,
+    requests: i64,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub(crate) struct AnalyticsSeries {
+    range: AnalyticsRange,
+    /// seconds between points
+    step: i64,
+    points: Vec<AnalyticsPoint>,
+    top_paths: Vec<TopEntry>,
+    top_referrers: Vec<TopEntry>,
+}
+
+/// buckets need to be already filtered by the project, deployment and range
+pub(crate) fn summarize(
+    buckets: &[(i64, BucketStats)],
+    range: AnalyticsRange,
+    now: i64,
+) -> AnalyticsSeries {
+    let step = range.step();
+    let first = (now - range.seconds()) / step * step + step;
+    let last = now / step * step;
+
+    let mut steps: Vec<BucketStats> = (first..=last)
+        .step_by(step as usize)
+        .map(|_| Default::default())
+        .collect();
+    let mut total = BucketStats::default();
+    for (start, stats) in buckets {
+        let index = usize::try_from((start - first).div_euclid(step));
+        if let Some(point) = index.ok().and_then(|index| steps.get_mut(index)) {
+            point.merge(stats);
+        }
+        total.merge(stats);
+    }
+
+    let points = steps
+        .into_iter()
+        .enumerate()
+        .map(|(index, stats)| AnalyticsPoint {
+            time: first + index as i64 * step,
+            requests: stats.requests,
+            status_1xx: stats.status[0],
+            status_2xx: stats.status[1],
+            status_3xx: stats.status[2],
+            status_4xx: stats.status[3],
+            status_5xx: stats.status[4],
+            p50: stats.percentile(0.5),
+            p95: stats.percentile(0.95),
+        })
+        .collect();
+    let into_entries = |map: &HashMap<String, i64>| {
+        top_entries(map, TOP_ENTRIES)
+            .into_iter()
+            .map(|(value, requests)| TopEntry { value, requests })
+            .collect()
+    };
+
+    AnalyticsSeries {
+        range,
+        step,
+        points,
+        top_paths: into_entries(&total.paths),
+        top_referrers: into_entries(&total.referrers),
+    }
+}
+
+#[cfg(test)]
+mod analytics_tests {
+    use crate::logging::RequestLog;
+
+    use super::{summarize, AnalyticsRange, BucketStats, BUCKET_SECONDS};
+
+    fn log(path: &str, status: u16, duration: u64, referer: Option<&str>) -> RequestLog {
+        RequestLog {
+            time: 0,
+            level: crate::logging::Level::INFO,
+            deployment: "deployment".to_owned().into(),
+            host: "app.example.com".to_owned(),
+            method: "GET".to_owned(),
+            path: path.to_owned(),
+            query: None,
+            status,
+            request_id: String::new(),
+            duration,
+            upstream_duration: None,
+            request_bytes: 0,
+            response_bytes: 0,
+            client_ip: None,
+            user_agent: None,
+            referer: referer.map(str::to_owned),
+            container: None,
+            cold_start: false,
+            cache_status: None,
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        let mut first = BucketStats::default();
+        for duration in [5, 5, 5, 40, 3000] {
+            first.record(&log("/", 200, duration, None));
+        }
+        first.record(&log("/missing", 404, 5, Some("https://search.com/?q=1")));
+        let mut second = BucketStats::default();
+        second.record(&log("/about", 500, 20, Some("https://app.example.com/")));
+
+        let now = 10 * 60 * 60;
+        let buckets = vec![
+            (now - 2 * BUCKET_SECONDS, first),
+            (now - BUCKET_SECONDS, second),
+        ];
+        let series = summarize(&buckets, AnalyticsRange::Hour, now);
+
+        assert_eq!(series.points.len(), 12);
+        let point = &series.points[9];
+        assert_eq!(point.requests, 6);
+        assert_eq!(point.status_2xx, 5);
+        assert_eq!(point.status_4xx, 1);
+        assert_eq!(point.p50, Some(10));
+        assert_eq!(point.p95, Some(5000));
+        assert_eq!(series.points[10].status_5xx, 1);
+
+        assert_eq!(series.top_paths[0].value, "/");
+        assert_eq!(series.top_paths[0].requests, 5);
+        assert_eq!(series.top_referrers.len(), 1);
+        assert_eq!(series.top_referrers[0].value, "search.com");
+    }
+}
@@ -1,20 +1,30 @@
 use actix_web::{
-    delete, get, patch, post,
-    web::{Data, Json, Path},
+    delete, get, patch, post, put,
+    web::{Data, Json, Path, Query},
     HttpMessage, HttpRequest, HttpResponse, Responder,
 };
 use futures::future::join_all;
+use serde::Deserialize;
+use utoipa::IntoParams;
 
 use crate::{
+    analytics::{summarize, AnalyticsRange},
     api::{
         bearer::{AdminRole, AnyRole},
         utils::{
             get_all_deployments, get_prod_deployment, get_prod_deployment_id, is_app_name_valid,
         },
-        AppState, ErrorResponse, FullProjectInfo, ProjectInfo,
+        AppState, ErrorResponse, FullProjectInfo, ProjectInfo, RateLimitInfo,
     },
     db::{nano_id::IntoOptString, EnvVar, InsertProject, UpdateProject},
-    tokens::TokenClaims,
+    env::normalize_env_target,
+    ip_filter::{Environment, IpRule},
+    log_drain::LogDrain,
+    notifications::NotificationChannel,
+    protection::{BasicAuth, BasicAuthCredentials},
+    rate_limit::RateLimits,
+    tokens::{Role, TokenClaims},
+    utils::now_in_seconds,
 };
 
 /// Get projects
@@ -81,6 +91,8 @@ async fn get_project(auth: AnyRole, state: Data<AppState>, name: Path<String>) -
                 prod_deployment_id: prod_deployment_id.into_opt_string(),
                 prod_deployment,
                 deployments,
+                build_timeout: project.build_timeout,
+                cancel_superseded_builds: project.cancel_superseded_builds,
             })
         }
         None => HttpResponse::NotFound().json(ErrorResponse::NotFound(format!("name = {name}"))),
@@ -92,7 +104,7 @@ async fn get_project(auth: AnyRole, state: Data<AppState>, name: Path<String>) -
     request_body = InsertProject,
     responses(
         (status = 201, description = "Project created successfully"),
-        (status = 400, description = "App name is not valid"),
+        (status = 400, description = "App name or env is not valid"),
     ),
     security(
         ("bearerAuth" = [])
@@ -102,10 +114,11 @@ async fn get_project(auth: AnyRole, state: Data<AppState>, name: Path<String>) -
 #[tracing::instrument]
 async fn create_project(
     _auth: AdminRole,
-    project: Json<InsertProject>,
+    mut project: Json<InsertProject>,
     state: Data<AppState>,
 ) -> impl Responder {
-    if is_app_name_valid(&project.name) {
+    let env_valid = project.env.iter_mut().all(normalize_env_target);
+    if is_app_name_valid(&project.name) && env_valid {
         state.db.insert_project(project.0).await;
         state.manager.full_sync_with_github().await;
         HttpResponse::Ok()
@@ -119,7 +132,7 @@ async fn create_project(
     request_body = UpdateProject,
     responses(
         (status = 200, description = "Project updated successfully"),
-        (status = 400, description = "App name is not valid"),
+        (status = 400, description = "App name or build timeout is not valid"),
         // (status = 409, description = "Todo with id already exists", body = ErrorResponse, example = json!(ErrorResponse::Conflict(String::from("id = 1"))))
     ),
     security(
@@ -139,7 +152,8 @@ async fn update_project(
         .name
         .as_ref()
         .is_none_or(|name| is_app_name_valid(name));
-    if valid_name {
+    let valid_timeout = project.0.build_timeout.map_or(true, |timeout| timeout > 0);
+    if valid_name && valid_timeout {
         let id = id.into_inner().into();
         state.db.update_project(&id, project.0).await;
         state.manager.sync_with_db().await; // TODO: review if its fine not doing a full sync with github here
@@ -172,7 +186,9 @@ async fn delete_project(
     HttpResponse::Ok()
 }
 
-/// Get env
+const MASKED_ENV_VALUE: &str = "********";
+
+/// Get env. Values are masked for users without the admin role
 #[utoipa::path(
     responses(
         (status = 200, description = "Env returned successfully", body = [EditedEnvVar]),
@@ -184,19 +200,31 @@ async fn delete_project(
 )]
 #[get("/api/apps/{id}/env")]
 #[tracing::instrument]
-async fn get_env(auth: AdminRole, state: Data<AppState>, id: Path<String>) -> impl Responder {
+async fn get_env(auth: AnyRole, state: Data<AppState>, id: Path<String>) -> impl Responder {
     let id = id.into_inner().into();
     match state.db.get_project(&id).await {
-        Some(project) => HttpResponse::Ok().json(project.env),
+        Some(mut project) => {
+            let cipher = state.db.env_cipher();
+            for var in &mut project.env {
+                var.value = match auth.0.role {
+                    Role::Admin => cipher
+                        .decrypt(&project.id, &var.name, &var.value)
+                        .unwrap_or_else(|_| MASKED_ENV_VALUE.to_owned()),
+                    Role::User => MASKED_ENV_VALUE.to_owned(),
+                };
+            }
+            HttpResponse::Ok().json(project.env)
+        }
         None => HttpResponse::NotFound().json(ErrorResponse::NotFound(format!("id = {id}"))),
     }
 }
 
-/// Upsert env
+/// Upsert env. Vars are replaced only if they have the same environment and branch
 #[utoipa::path(
     request_body = EnvVar,
     responses(
         (status = 200, description = "Env upserted successfully"),
+        (status = 400, description = "Branch specific vars can't target production"),
     ),
     security(
         ("bearerAuth" = [])
@@ -206,18 +234,31 @@ async fn get_env(auth: AdminRole, state: Data<AppState>, id: Path<String>) -> im
 #[tracing::instrument]
 async fn upsert_env(
     auth: AdminRole,
-    env: Json<EnvVar>,
+    mut env: Json<EnvVar>,
     state: Data<AppState>,
     id: Path<String>,
 ) -> impl Responder {
+    if !normalize_env_target(&mut env.0) {
+        return HttpResponse::BadRequest();
+    }
     let id = id.into_inner().into();
-    state.db.upsert_env(&id, &env.0.name, &env.0.value).await;
+    state.db.upsert_env(&id, &env.0).await;
     // state.manager.sync_with_db().await; // TODO: review if its fine not calling sync here
     HttpResponse::Ok()
 }
 
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct EnvTargetQuery {
+    /// delete the value for every environment if not set
+    environment: Option<Environment>,
+    /// delete the value for this branch or pattern
+    branch: Option<String>,
+}
+
 /// Delete env
 #[utoipa::path(
+    params(EnvTargetQuery),
     responses(
         (status = 200, description = "Env deleted successfully"),
     ),
@@ -231,8 +272,354 @@ async fn delete_env(
     auth: AdminRole,
     state: Data<AppState>,
     path: Path<(String, String)>,
+    query: Query<EnvTargetQuery>,
 ) -> impl Responder {
-    state.db.delete_env(&(path.0.clone().into()), &path.1).await;
+    // branch specific vars are always stored as previews
+    let environment = match query.branch {
+        Some(_) => Some(Environment::Preview),
+        None => query.environment,
+    };
+    state
+        .db
+        .delete_env(
+            &(path.0.clone().into()),
+            &path.1,
+            environment,
+            query.branch.as_deref(),
+        )
+        .await;
     // state.manager.sync_with_db().await; // TODO: review if its fine not calling sync here
     HttpResponse::Ok()
 }
+
+/// Protect private deployments with basic auth
+#[utoipa::path(
+    request_body = BasicAuthCredentials,
+    responses(
+        (status = 200, description = "Protection updated successfully"),
+        (status = 400, description = "Username or password are empty"),
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[put("/api/apps/{id}/protection")]
+#[tracing::instrument]
+async fn update_protection(
+    auth: AdminRole,
+    credentials: Json<BasicAuthCredentials>,
+    state: Data<AppState>,
+    id: Path<String>,
+) -> impl Responder {
+    let credentials = credentials.0;
+    // a colon in the username would make the Authorization header ambiguous
+    if credentials.username.is_empty()
+        || credentials.username.contains(':')
+        || credentials.password.is_empty()
+    {
+        return HttpResponse::BadRequest().finish();
+    }
+    let basic_auth = BasicAuth {
+        password_hash: credentials.hash_password(),
+        username: credentials.username,
+    };
+    let id = id.into_inner().into();
+    state
+        .db
+        .update_project_basic_auth(&id, Some(basic_auth))
+        .await;
+    state.manager.sync_with_db().await;
+    HttpResponse::Ok().finish()
+}
+
+/// Remove basic auth protection
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Protection removed successfully"),
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[delete("/api/apps/{id}/protection")]
+#[tracing::instrument]
+async fn delete_protection(
+    auth: AdminRole,
+    state: Data<AppState>,
+    id: Path<String>,
+) -> impl Responder {
+    let id = id.into_inner().into();
+    state.db.update_project_basic_auth(&id, None).await;
+    state.manager.sync_with_db().await;
+    HttpResponse::Ok().finish()
+}
+
+/// Get IP rules
+#[utoipa::path(
+    responses(
+        (status = 200, description = "IP rules returned successfully", body = [IpRule]),
+        (status = 404, description = "Project not found", body = ErrorResponse)
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[get("/api/apps/{id}/ip-rules")]
+#[tracing::instrument]
+async fn get_ip_rules(auth: AdminRole, state: Data<AppState>, id: Path<String>) -> impl Responder {
+    let id = id.into_inner().into();
+    match state.db.get_project(&id).await {
+        Some(project) => HttpResponse::Ok().json(project.ip_rules),
+        None => HttpResponse::NotFound().json(ErrorResponse::NotFound(format!("id = {id}"))),
+    }
+}
+
+/// Replace IP rules
+#[utoipa::path(
+    request_body = [IpRule],
+    responses(
+        (status = 200, description = "IP rules updated successfully"),
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[put("/api/apps/{id}/ip-rules")]
+#[tracing::instrument]
+async fn update_ip_rules(
+    auth: AdminRole,
+    rules: Json<Vec<IpRule>>,
+    state: Data<AppState>,
+    id: Path<String>,
+) -> impl Responder {
+    let id = id.into_inner().into();
+    state.db.update_project_ip_rules(&id, &rules.0).await;
+    state.manager.sync_with_db().await;
+    HttpResponse::Ok().finish()
+}
+
+/// Get rate limits and the number of requests allowed and limited since prezel started
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Rate limits returned successfully", body = RateLimitInfo),
+        (status = 404, description = "Project not found", body = ErrorResponse)
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[get("/api/apps/{id}/rate-limits")]
+#[tracing::instrument]
+async fn get_rate_limits(
+    auth: AdminRole,
+    state: Data<AppState>,
+    id: Path<String>,
+) -> impl Responder {
+    let id = id.into_inner().into();
+    match state.db.get_project(&id).await {
+        Some(project) => HttpResponse::Ok().json(RateLimitInfo {
+            limits: project.rate_limits,
+            counters: state.manager.rate_limiter.get_counters(&id),
+        }),
+        None => HttpResponse::NotFound().json(ErrorResponse::NotFound(format!("id = {id}"))),
+    }
+}
+
+/// Update rate limits
+#[utoipa::path(
+    request_body = RateLimits,
+    responses(
+        (status = 200, description = "Rate limits updated successfully"),
+        (status = 400, description = "Requests and seconds need to be greater than 0"),
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[put("/api/apps/{id}/rate-limits")]
+#[tracing::instrument]
+async fn update_rate_limits(
+    auth: AdminRole,
+    rate_limits: Json<RateLimits>,
+    state: Data<AppState>,
+    id: Path<String>,
+) -> impl Responder {
+    if !rate_limits.is_valid() {
+        return HttpResponse::BadRequest().finish();
+    }
+    let id = id.into_inner().into();
+    state
+        .db
+        .update_project_rate_limits(&id, &rate_limits.0)
+        .await;
+    state.manager.sync_with_db().await;
+    HttpResponse::Ok().finish()
+}
+
+/// Get log drains
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Log drains returned successfully", body = [LogDrain]),
+        (status = 404, description = "Project not found", body = ErrorResponse)
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[get("/api/apps/{id}/log-drains")]
+#[tracing::instrument]
+async fn get_log_drains(
+    auth: AdminRole,
+    state: Data<AppState>,
+    id: Path<String>,
+) -> impl Responder {
+    let id = id.into_inner().into();
+    match state.db.get_project(&id).await {
+        Some(project) => HttpResponse::Ok().json(project.log_drains),
+        None => HttpResponse::NotFound().json(ErrorResponse::NotFound(format!("id = {id}"))),
+    }
+}
+
+/// Replace log drains
+#[utoipa::path(
+    request_body = [LogDrain],
+    responses(
+        (status = 200, description = "Log drains updated successfully"),
+        (status = 400, description = "Invalid URL or address"),
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[put("/api/apps/{id}/log-drains")]
+#[tracing::instrument]
+async fn update_log_drains(
+    auth: AdminRole,
+    log_drains: Json<Vec<LogDrain>>,
+    state: Data<AppState>,
+    id: Path<String>,
+) -> impl Responder {
+    if !log_drains.iter().all(LogDrain::is_valid) {
+        return HttpResponse::BadRequest().finish();
+    }
+    let id = id.into_inner().into();
+    state.db.update_project_log_drains(&id, &log_drains).await;
+    HttpResponse::Ok().finish()
+}
+
+/// Get notification channels
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Notification channels returned successfully", body = [NotificationChannel]),
+        (status = 404, description = "Project not found", body = ErrorResponse)
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[get("/api/apps/{id}/notifications")]
+#[tracing::instrument]
+async fn get_notification_channels(
+    auth: AdminRole,
+    state: Data<AppState>,
+    id: Path<String>,
+) -> impl Responder {
+    let id = id.into_inner().into();
+    match state.db.get_project(&id).await {
+        Some(project) => HttpResponse::Ok().json(project.notification_channels),
+        None => HttpResponse::NotFound().json(ErrorResponse::NotFound(format!("id = {id}"))),
+    }
+}
+
+/// Replace notification channels
+#[utoipa::path(
+    request_body = [NotificationChannel],
+    responses(
+        (status = 200, description = "Notification channels updated successfully"),
+        (status = 400, description = "Invalid URL"),
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[put("/api/apps/{id}/notifications")]
+#[tracing::instrument]
+async fn update_notification_channels(
+    auth: AdminRole,
+    channels: Json<Vec<NotificationChannel>>,
+    state: Data<AppState>,
+    id: Path<String>,
+) -> impl Responder {
+    if !channels.iter().all(NotificationChannel::is_valid) {
+        return HttpResponse::BadRequest().finish();
+    }
+    let id = id.into_inner().into();
+    state
+        .db
+        .update_project_notification_channels(&id, &channels)
+        .await;
+    HttpResponse::Ok().finish()
+}
+
+/// Get the last notification delivery attempts, most recent first
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Delivery attempts returned successfully", body = [NotificationDelivery]),
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[get("/api/apps/{id}/notifications/deliveries")]
+#[tracing::instrument]
+async fn get_notification_deliveries(
+    auth: AdminRole,
+    state: Data<AppState>,
+    id: Path<String>,
+) -> impl Responder {
+    let id = id.into_inner().into();
+    let deliveries = state.db.get_notification_deliveries(&id, 100).await;
+    HttpResponse::Ok().json(deliveries)
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AnalyticsQuery {
+    /// 24h by default
+    range: Option<AnalyticsRange>,
+    /// only include the requests to this deployment
+    deployment: Option<String>,
+}
+
+/// Get request analytics
+#[utoipa::path(
+    params(AnalyticsQuery),
+    responses(
+        (status = 200, description = "Analytics returned successfully", body = AnalyticsSeries),
+        (status = 404, description = "Project not found", body = ErrorResponse)
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[get("/api/apps/{id}/analytics")]
+#[tracing::instrument]
+async fn get_analytics(
+    auth: AnyRole,
+    state: Data<AppState>,
+    id: Path<String>,
+    query: Query<AnalyticsQuery>,
+) -> impl Responder {
+    let id = id.into_inner().into();
+    if state.db.get_project(&id).await.is_none() {
+        return HttpResponse::NotFound().json(ErrorResponse::NotFound(format!("id = {id}")));
+    }
+    let range = query.range.unwrap_or_default();
+    let deployment = query.deployment.clone().map(|deployment| deployment.into());
+    let now = now_in_seconds();
+    let buckets = state
+        .db
+        .get_analytics_buckets(&id, deployment.as_ref(), now - range.seconds())
+        .await;
+    HttpResponse::Ok().json(summarize(&buckets, range, now))
+}
@@ -1,21 +1,34 @@
 use actix_web::{
     delete, get, post,
-    web::{Data, Json, Path},
+    web::{Data, Json, Path, Query},
     HttpResponse, Responder,
 };
+use serde::Deserialize;
+use utoipa::IntoParams;
 
 use crate::{
     api::{
         bearer::{AdminRole, AnyRole},
         utils::clone_deployment,
-        AppState,
+        AppState, ErrorResponse, ShareLink, ShareRequest,
     },
     logging::{read_request_event_logs, Log},
+    protection::{generate_share_token, MAX_SHARE_SECONDS, SHARE_QUERY_PARAM},
+    utils::now_in_seconds,
 };
 
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RedeployQuery {
+    /// build from scratch instead of reusing the layers of previous builds
+    #[serde(default)]
+    no_cache: bool,
+}
+
 // TODO: this should take the id from the PATH, should not be POST I guess
 /// Re-deploy based on an existing deployment
 #[utoipa::path(
+    params(RedeployQuery),
     request_body = String,
     responses(
         (status = 200, description = "Deployment redeployed successfully"),
@@ -30,8 +43,9 @@ async fn redeploy(
     auth: AdminRole,
     deployment: Json<String>,
     state: Data<AppState>,
+    query: Query<RedeployQuery>,
 ) -> impl Responder {
-    clone_deployment(&state.db, &deployment.0.into()).await;
+    clone_deployment(&state.db, &deployment.0.into(), query.no_cache).await;
     state.manager.sync_with_db().await;
     HttpResponse::Ok()
 }
@@ -57,6 +71,76 @@ async fn delete_deployment(
     HttpResponse::Ok()
 }
 
+/// Cancel the build of a deployment, whether it is queued or running
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Build cancelled successfully"),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+        (status = 409, description = "Deployment is not queued or building", body = ErrorResponse),
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[post("/api/deployments/{id}/cancel")]
+#[tracing::instrument]
+async fn cancel_deployment(
+    auth: AdminRole,
+    state: Data<AppState>,
+    id: Path<String>,
+) -> impl Responder {
+    let id = id.into_inner().into();
+    match state.manager.get_deployment(&id).await {
+        Some(deployment) => {
+            if deployment.app_container.cancel_build().await {
+                HttpResponse::Ok().finish()
+            } else {
+                HttpResponse::Conflict().json(ErrorResponse::Conflict(format!(
+                    "deployment {id} is not queued or building"
+                )))
+            }
+        }
+        None => HttpResponse::NotFound().json(ErrorResponse::NotFound(format!("id = {id}"))),
+    }
+}
+
+/// Create a share link granting temporary access to a private deployment
+#[utoipa::path(
+    request_body = ShareRequest,
+    responses(
+        (status = 200, description = "Share link created successfully", body = ShareLink),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[post("/api/deployments/{id}/share")]
+#[tracing::instrument]
+async fn share_deployment(
+    auth: AdminRole,
+    request: Json<ShareRequest>,
+    state: Data<AppState>,
+    id: Path<String>,
+) -> impl Responder {
+    let id = id.into_inner().into();
+    match state.db.get_deployment_with_project(&id).await {
+        Some(deployment) => {
+            let box_domain = &state.manager.box_domain;
+            let host = deployment.get_app_hostname(box_domain);
+            let seconds = request.expires_in.clamp(1, MAX_SHARE_SECONDS);
+            let expires = now_in_seconds() + seconds;
+            let token = generate_share_token(&host, expires, &state.secret);
+            let base_url = deployment.get_app_base_url(box_domain);
+            HttpResponse::Ok().json(ShareLink {
+                url: format!("{base_url}?{SHARE_QUERY_PARAM}={token}"),
+                expires,
+            })
+        }
+        None => HttpResponse::NotFound().json(ErrorResponse::NotFound(format!("id = {id}"))),
+    }
+}
+
 /// Sync deployments with github
 #[utoipa::path(
     responses(
@@ -143,3 +227,26 @@ async fn get_deployment_build_logs(
         .collect();
     HttpResponse::Ok().json(logs)
 }
+
+/// Get the timeline of a deployment, from oldest to latest
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Fetched deployment events", body = [DeploymentEvent]),
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[get("/api/deployments/{id}/events")]
+#[tracing::instrument]
+async fn get_deployment_events(
+    auth: AnyRole,
+    state: Data<AppState>,
+    id: Path<String>,
+) -> impl Responder {
+    let events = state
+        .db
+        .get_deployment_events(&id.into_inner().into())
+        .await;
+    HttpResponse::Ok().json(events)
+}
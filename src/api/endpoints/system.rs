@@ -1,6 +1,18 @@
-use actix_web::{get, HttpResponse, Responder};
+use actix_web::{
+    get, put,
+    web::{Data, Json},
+    HttpResponse, Responder,
+};
 
-use crate::{api::bearer::AnyRole, docker::get_container_execution_logs};
+use crate::{
+    api::{
+        bearer::{AdminRole, AnyRole},
+        AppState, LogFilterInfo,
+    },
+    docker::get_container_execution_logs,
+    metrics::{get_disk_usage, render, Snapshot},
+    paths::get_disk_usage_paths,
+};
 
 /// Get system logs
 #[utoipa::path(
@@ -16,3 +28,71 @@ async fn get_logs(_auth: AnyRole) -> impl Responder {
     let logs = get_container_execution_logs("prezel").await;
     HttpResponse::Ok().json(logs.collect::<Vec<_>>())
 }
+
+/// Get metrics in the Prometheus text format
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Fetched metrics", body = String, content_type = "text/plain")
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[get("/metrics")]
+async fn get_metrics(_auth: AdminRole, state: Data<AppState>) -> impl Responder {
+    let disk_usage = tokio::task::spawn_blocking(|| {
+        get_disk_usage_paths()
+            .into_iter()
+            .map(|(name, path)| (name.to_owned(), get_disk_usage(&path)))
+            .collect()
+    })
+    .await
+    .unwrap_or_default();
+    let snapshot = Snapshot {
+        containers: state.manager.get_container_counts().await,
+        certificates: state.manager.get_certificate_expiries().await,
+        disk_usage,
+    };
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render(&snapshot))
+}
+
+/// Get the log filter of the instance
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Fetched log filter", body = LogFilterInfo)
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[get("/api/system/log-filter")]
+async fn get_log_filter(_auth: AdminRole, state: Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(LogFilterInfo {
+        filter: state.log_filter.get(),
+    })
+}
+
+/// Update the log filter of the instance until the next restart
+#[utoipa::path(
+    request_body = LogFilterInfo,
+    responses(
+        (status = 200, description = "Log filter updated successfully"),
+        (status = 400, description = "Invalid filter directives"),
+    ),
+    security(
+        ("bearerAuth" = [])
+    )
+)]
+#[put("/api/system/log-filter")]
+async fn update_log_filter(
+    _auth: AdminRole,
+    info: Json<LogFilterInfo>,
+    state: Data<AppState>,
+) -> impl Responder {
+    match state.log_filter.set(&info.filter) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(error) => HttpResponse::BadRequest().body(error.to_string()),
+    }
+}
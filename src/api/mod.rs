@@ -1,17 +1,30 @@
+use std::collections::HashMap;
+
 use actix_web::web::{Data, ServiceConfig};
 use endpoints::{apps, deployments, system, version};
 use octocrab::models::Repository as CrabRepository;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use utoipa::{OpenApi, ToSchema};
 
 use crate::{
+    analytics::{AnalyticsPoint, AnalyticsRange, AnalyticsSeries, TopEntry},
     db::{
-        BuildResult, Db, DeploymentWithProject, EditedEnvVar, EnvVar, InsertProject, UpdateProject,
+        nano_id::NanoId, BuildResult, Db, DeploymentEvent, DeploymentEventKind,
+        DeploymentWithProject, EditedEnvVar, EnvScope, EnvVar, InsertProject, UpdateProject,
+    },
+    deployments::{
+        deployment::Deployment,
+        manager::{Manager, QueuedBuild},
     },
-    deployments::{deployment::Deployment, manager::Manager},
     github::Github,
-    logging::{Level, Log},
+    ip_filter::{Environment, IpAction, IpRule},
+    log_drain::{LogDrain, SyslogProtocol},
+    logging::{Level, Log, RequestDetails},
+    notifications::{NotificationChannel, NotificationDelivery, NotificationEvent},
+    protection::BasicAuthCredentials,
+    rate_limit::{RateLimit, RateLimitCounters, RateLimits},
     sqlite_db::DbAccess,
+    traces::LogFilter,
     utils::PlusHttps,
 };
 
@@ -29,6 +42,9 @@ pub(crate) const API_PORT: u16 = 5045;
         version::get_version,
         version::update_version,
         system::get_logs,
+        system::get_metrics,
+        system::get_log_filter,
+        system::update_log_filter,
         apps::get_projects,
         apps::get_project,
         apps::create_project,
@@ -37,13 +53,28 @@ pub(crate) const API_PORT: u16 = 5045;
         apps::get_env,
         apps::upsert_env,
         apps::delete_env,
+        apps::update_protection,
+        apps::delete_protection,
+        apps::get_ip_rules,
+        apps::update_ip_rules,
+        apps::get_rate_limits,
+        apps::update_rate_limits,
+        apps::get_log_drains,
+        apps::update_log_drains,
+        apps::get_notification_channels,
+        apps::update_notification_channels,
+        apps::get_notification_deliveries,
+        apps::get_analytics,
         deployments::redeploy,
         deployments::delete_deployment,
+        deployments::cancel_deployment,
+        deployments::share_deployment,
         deployments::sync,
         deployments::get_deployment_logs,
-        deployments::get_deployment_build_logs
+        deployments::get_deployment_build_logs,
+        deployments::get_deployment_events
     ),
-    components(schemas(ProjectInfo, FullProjectInfo, ErrorResponse, UpdateProject, Repository, ApiDeployment, Log, Level, Status, InsertProject, LibsqlDb, EnvVar, EditedEnvVar)),
+    components(schemas(ProjectInfo, FullProjectInfo, ErrorResponse, UpdateProject, Repository, ApiDeployment, DeploymentEvent, DeploymentEventKind, Log, RequestDetails, Level, Status, InsertProject, LibsqlDb, EnvVar, EnvScope, EditedEnvVar, BasicAuthCredentials, ShareRequest, ShareLink, IpRule, IpAction, Environment, RateLimitInfo, RateLimits, RateLimit, RateLimitCounters, LogDrain, SyslogProtocol, NotificationChannel, NotificationEvent, NotificationDelivery, AnalyticsRange, AnalyticsSeries, AnalyticsPoint, TopEntry, LogFilterInfo)),
     tags(
         (name = "prezel", description = "Prezel management endpoints.")
     ),
@@ -57,6 +88,9 @@ fn configure_service(store: Data<AppState>) -> impl FnOnce(&mut ServiceConfig) {
             .service(version::get_version)
             .service(version::update_version)
             .service(system::get_logs)
+            .service(system::get_metrics)
+            .service(system::get_log_filter)
+            .service(system::update_log_filter)
             .service(apps::get_projects)
             .service(apps::get_project)
             .service(apps::create_project)
@@ -65,11 +99,26 @@ fn configure_service(store: Data<AppState>) -> impl FnOnce(&mut ServiceConfig) {
             .service(apps::get_env)
             .service(apps::upsert_env)
             .service(apps::delete_env)
+            .service(apps::update_protection)
+            .service(apps::delete_protection)
+            .service(apps::get_ip_rules)
+            .service(apps::update_ip_rules)
+            .service(apps::get_rate_limits)
+            .service(apps::update_rate_limits)
+            .service(apps::get_log_drains)
+            .service(apps::update_log_drains)
+            .service(apps::get_notification_channels)
+            .service(apps::update_notification_channels)
+            .service(apps::get_notification_deliveries)
+            .service(apps::get_analytics)
             .service(deployments::redeploy)
             .service(deployments::delete_deployment)
+            .service(deployments::cancel_deployment)
+            .service(deployments::share_deployment)
             .service(deployments::sync)
             .service(deployments::get_deployment_logs)
-            .service(deployments::get_deployment_build_logs);
+            .service(deployments::get_deployment_build_logs)
+            .service(deployments::get_deployment_events);
         // If I add anything here also need to add it in api/mod.rs
     }
 }
@@ -81,6 +130,7 @@ pub(crate) struct AppState {
     pub(crate) manager: Manager,
     pub(crate) github: Github,
     pub(crate) secret: String,
+    pub(crate) log_filter: LogFilter,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -101,6 +151,8 @@ pub(crate) enum Status {
     Building,
     Ready,
     Failed,
+    Cancelled,
+    Skipped,
 }
 
 impl ToString for Status {
@@ -112,11 +164,38 @@ impl ToString for Status {
             Self::StandBy => "stand by",
             Self::Ready => "ready",
             Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+            Self::Skipped => "skipped",
         };
         string.to_owned()
     }
 }
 
+#[derive(Deserialize, Debug, ToSchema)]
+struct ShareRequest {
+    /// seconds until the link expires, capped at 30 days
+    expires_in: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ShareLink {
+    url: String,
+    /// epoch in seconds
+    expires: i64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+struct LogFilterInfo {
+    /// RUST_LOG style directives, e.g. info,main::proxy=debug
+    filter: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct RateLimitInfo {
+    limits: RateLimits,
+    counters: RateLimitCounters,
+}
+
 #[derive(Serialize, ToSchema)]
 struct LibsqlDb {
     url: String,
@@ -142,6 +221,12 @@ struct ApiDeployment {
     created: i64,
     build_started: Option<i64>,
     build_finished: Option<i64>,
+    /// docker image of the last successful build
+    image_id: Option<String>,
+    /// only for queued deployments, starting at 1 for the next one to be built
+    queue_position: Option<usize>,
+    /// only for queued deployments, in seconds
+    estimated_wait: Option<i64>,
 }
 
 // TODO: move this somewhere else
@@ -154,8 +239,10 @@ impl ApiDeployment {
         is_prod: bool,
         box_domain: &str,
         manager: &Manager,
+        queued_builds: &HashMap<NanoId, QueuedBuild>,
         access: DbAccess,
     ) -> Self {
+        let queued_build = deployment.and_then(|deployment| queued_builds.get(&deployment.id));
         let (status, url, prod_url, custom_urls, app_container, libsql_db) =
             if let Some(deployment) = deployment {
                 let container_status = deployment.app_container.status.read().await.clone();
@@ -196,6 +283,8 @@ impl ApiDeployment {
                 let status = match db_deployment.result {
                     Some(BuildResult::Failed) => Status::Failed,
                     Some(BuildResult::Built) => Status::Built,
+                    Some(BuildResult::Cancelled) => Status::Cancelled,
+                    Some(BuildResult::Skipped) => Status::Skipped,
                     None => Status::Queued,
                 };
                 (status, None, None, vec![], None, None)
@@ -218,6 +307,9 @@ impl ApiDeployment {
             created: db_deployment.created,
             build_started: db_deployment.build_started,
             build_finished: db_deployment.build_finished,
+            image_id: db_deployment.image_id.clone(),
+            queue_position: queued_build.map(|queued| queued.position),
+            estimated_wait: queued_build.and_then(|queued| queued.estimated_wait),
         }
     }
 }
@@ -287,4 +379,7 @@ struct FullProjectInfo {
     prod_deployment: Option<ApiDeployment>,
     /// All project deployments sorted by created datetime descending
     deployments: Vec<ApiDeployment>,
+    /// in seconds, missing if the default timeout is used
+    build_timeout: Option<i64>,
+    cancel_superseded_builds: bool,
 }
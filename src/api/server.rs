@@ -17,6 +17,7 @@ use crate::{
     db::Db,
     deployments::manager::Manager,
     github::Github,
+    traces::LogFilter,
 };
 
 use super::ApiDoc;
@@ -41,12 +42,14 @@ pub(crate) async fn run_api_server(
     github: Github,
     api_hostname: &str,
     secret: String,
+    log_filter: LogFilter,
 ) -> Result<(), impl Error> {
     let state = AppState {
         db,
         manager: manager.clone(),
         github,
         secret,
+        log_filter,
     };
 
     let base_url = format!("https://{api_hostname}");
@@ -2,6 +2,7 @@ use futures::{stream, StreamExt};
 
 use crate::{
     db::{nano_id::NanoId, Db, InsertDeployment, Project},
+    env::resolve_deployment_env,
     sqlite_db::DbAccess,
 };
 
@@ -28,6 +29,7 @@ pub(super) async fn get_prod_deployment(
     let deployment = manager.get_prod_deployment(project).await?;
     let db_deployment = db.get_deployment_with_project(&deployment.id).await?;
     let is_prod = true;
+    let queued_builds = manager.get_queued_builds().await;
     Some(
         ApiDeployment::from(
             Some(deployment).as_ref(),
@@ -35,6 +37,7 @@ pub(super) async fn get_prod_deployment(
             is_prod,
             box_domain,
             &manager,
+            &queued_builds,
             access,
         )
         .await,
@@ -48,6 +51,8 @@ pub(super) async fn get_all_deployments(
     access: DbAccess,
 ) -> Vec<ApiDeployment> {
     let box_domain = &manager.box_domain;
+    // the queue is the same for every deployment, so it is only computed once
+    let queued_builds = &manager.get_queued_builds().await;
 
     let db_deployments = db.get_deployments_with_project().await;
     let mut deployments: Vec<_> =
@@ -66,6 +71,7 @@ pub(super) async fn get_all_deployments(
                     is_prod,
                     box_domain,
                     &manager,
+                    queued_builds,
                     access,
                 )
                 .await
@@ -76,17 +82,26 @@ pub(super) async fn get_all_deployments(
     deployments
 }
 
-pub(crate) async fn clone_deployment(db: &Db, deployment_id: &NanoId) -> Option<()> {
+pub(crate) async fn clone_deployment(
+    db: &Db,
+    deployment_id: &NanoId,
+    no_cache: bool,
+) -> Option<()> {
     let deployment = db.get_deployment(deployment_id).await?;
     let project = db.get_project(&deployment.project).await?;
 
     let insert = InsertDeployment {
-        env: project.env.clone(),
+        env: resolve_deployment_env(
+            &project.env,
+            &deployment.branch,
+            deployment.is_default_branch(),
+        ),
         sha: deployment.sha.clone(),
         branch: deployment.branch.clone(),
         default_branch: deployment.default_branch,
         timestamp: deployment.timestamp,
         project: deployment.project,
+        no_cache,
     };
     db.insert_deployment(insert).await;
     Some(())
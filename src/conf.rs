@@ -1,3 +1,4 @@
+use ipnet::IpNet;
 use serde::Deserialize;
 use std::{fs, io};
 
@@ -6,8 +7,39 @@ use crate::paths::get_config_path;
 #[derive(Deserialize, Clone, Debug)]
 pub(crate) struct Conf {
     pub(crate) secret: String,
+    /// only needed on the first start after changing the secret, to re-wrap the env encryption key
+    pub(crate) previous_secret: Option<String>,
     pub(crate) hostname: String,
     pub(crate) provider: String,
+    /// proxies in front of prezel whose X-Forwarded-For header can be trusted
+    #[serde(default)]
+    pub(crate) trusted_proxies: Vec<IpNet>,
+    #[serde(default)]
+    pub(crate) log: LogConf,
+    /// number of deployments that can be built at the same time
+    #[serde(default = "default_build_concurrency")]
+    pub(crate) build_concurrency: usize,
+}
+
+fn default_build_concurrency() -> usize {
+    1
+}
+
+/// Logs of the instance itself. RUST_LOG and PREZEL_LOG_FORMAT take precedence over these
+#[derive(Deserialize, Clone, Debug, Default)]
+pub(crate) struct LogConf {
+    #[serde(default)]
+    pub(crate) format: LogFormat,
+    /// RUST_LOG style directives, info by default
+    pub(crate) filter: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    #[default]
+    Text,
+    Json,
 }
 
 impl Conf {
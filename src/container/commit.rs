@@ -1,40 +1,53 @@
-use anyhow::ensure;
+use anyhow::{anyhow, bail, ensure};
 use nixpacks::{
     create_docker_image,
     nixpacks::{builder::docker::DockerBuilderOptions, plan::generator::GeneratePlanOptions},
 };
 use std::{
+    collections::HashMap,
     future::Future,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     pin::Pin,
+    sync::{Arc, RwLock},
 };
 use tempfile::TempDir;
 use tokio::fs;
+use tracing::error;
 
 use crate::{
     db::nano_id::NanoId,
-    docker::{get_managed_image_id, ImageName},
+    deployments::config::{BuildConfig, DeploymentConfig},
+    docker::{delete_image, get_managed_image_id, BuildOptions, ImageName},
     env::EnvVars,
     github::Github,
     hooks::StatusHooks,
+    redact::Redactor,
     sqlite_db::{BranchSqliteDb, ProdSqliteDb, SqliteDbSetup},
+    traces::get_container_otel_env,
 };
 
 use super::{
     build_dockerfile, BuildResult, Container, ContainerConfig, ContainerSetup, ContainerStatus,
-    DeploymentHooks, WorkerHandle,
+    DeploymentHooks, SharedProxySettings, WorkerHandle,
 };
 
 #[derive(Clone, Debug)]
 pub(crate) struct CommitContainer {
     github: Github,
     deployment: NanoId,
+    project: NanoId,
+    no_cache: bool,
+    build_args: EnvVars,
+    build_secrets: EnvVars,
+    /// only passed to the build if prezel.json opts in
+    db_env: EnvVars,
     // main_db_file: HostFile,
     branch_db: Option<BranchSqliteDb>,
     pub(crate) repo_id: i64,
     pub(crate) sha: String,
-    env: EnvVars,
     root: String,
+    default_branch: bool,
+    settings: SharedProxySettings,
 }
 
 impl CommitContainer {
@@ -46,10 +59,14 @@ impl CommitContainer {
         repo_id: i64,
         sha: String,
         deployment: NanoId,
+        project: NanoId,
+        no_cache: bool,
+        build_args: EnvVars,
+        build_secrets: EnvVars,
         env: EnvVars, // TODO: this is duplicated in ContainerConfig...
         root: String,
         branch: bool,
-        public: bool, // TODO: should not this be in ContainerConfig
+        config: Option<DeploymentConfig>,
         prod_db: &ProdSqliteDb,
         db_url: &str,
         // cloned_db_file: Option<HostFile>,
@@ -63,28 +80,43 @@ impl CommitContainer {
         } else {
             (None, prod_db.setup.auth.get_permanent_token().to_owned())
         };
-        let default_env = [
+        let db_env: EnvVars = [
             ("PREZEL_DB_URL", db_url),
             ("PREZEL_DB_AUTH_TOKEN", &token),
             ("PREZEL_LIBSQL_URL", db_url),
             ("PREZEL_LIBSQL_AUTH_TOKEN", &token),
             ("ASTRO_DB_REMOTE_URL", db_url),
             ("ASTRO_DB_APP_TOKEN", &token),
-            ("HOST", "0.0.0.0"),
-            ("PORT", "80"),
         ]
         .as_ref()
         .into();
-        let extended_env = env + default_env;
+        let default_env = db_env.clone() + [("HOST", "0.0.0.0"), ("PORT", "80")].as_ref().into();
+        let build_values = build_args.values().chain(build_secrets.values());
+        let redactor = Redactor::new(build_values.chain(env.values()).chain([&token]));
+        let hooks = hooks.with_redactor(redactor.clone());
+        let extended_env = env + default_env + get_container_otel_env();
+
+        // until prezel.json is read from the build context we don't know if the deployment
+        // should be public, so we keep it private to be on the safe side
+        let settings = config
+            .map(|config| config.get_proxy_settings(!branch))
+            .unwrap_or_default();
+        let settings = Arc::new(RwLock::new(settings));
 
         let builder = Self {
             github,
             branch_db,
             deployment: deployment.clone(),
+            project,
+            no_cache,
+            build_args,
+            build_secrets,
+            db_env,
             repo_id,
             sha,
-            env: extended_env.clone(),
             root,
+            default_branch: !branch,
+            settings: settings.clone(),
         };
 
         Container::new(
@@ -96,10 +128,11 @@ impl CommitContainer {
                 initial_status,
                 command: None,
                 result,
+                redactor,
             },
             build_queue,
             Some(deployment),
-            public,
+            settings,
             hooks,
         )
     }
@@ -121,54 +154,153 @@ impl CommitContainer {
             // if build and docker workers do not overlap, I'm safe
             // the problem might be grabbing this id at the same time the image is being removed
             // the same happens with containers
-            Ok(image)
-        } else {
-            let tempdir = TempDir::new()?;
-            let path = tempdir.as_ref();
-            let path = self.build_context(path).await?;
-            let image = build_dockerfile(name, &path, self.env.clone(), &mut |chunk| async {
-                if let Some(stream) = chunk.stream {
-                    hooks.on_build_log(&stream, false).await
-                } else if let Some(error) = chunk.error {
-                    hooks.on_build_log(&error, true).await
-                }
-            })
-            .await?;
-            Ok(image)
+            // the proxy settings already come from the config stored in the db for this image
+            return Ok(image);
         }
+
+        let tempdir = TempDir::new()?;
+        let repo = tempdir.as_ref();
+        let root = self.download(repo).await?;
+        let config = self.load_config(&root, hooks.as_ref()).await?;
+        let build_config = config.get_build_config();
+        let (path, dockerfile) = self.build_context(repo, &root, build_config).await?;
+        let options = BuildOptions {
+            dockerfile,
+            args: self.get_build_args(build_config),
+            secrets: self.get_build_secrets(build_config),
+            target: build_config.target.clone(),
+            no_cache: self.no_cache,
+        };
+        build_dockerfile(name, &path, options, &mut |chunk| async {
+            if let Some(stream) = chunk.stream {
+                hooks.on_build_log(&stream, false).await
+            } else if let Some(error) = chunk.error {
+                hooks.on_build_log(&error, true).await
+            }
+        })
+        .await
+    }
+
+    /// the build context lives in a TempDir, so the only leftover can be the image if docker
+    /// managed to tag it before the build was stopped
+    #[tracing::instrument]
+    async fn cleanup(&self) {
+        let name: ImageName = self.deployment.to_string().into();
+        if let Some(image) = get_managed_image_id(&name).await {
+            if let Err(error) = delete_image(&image).await {
+                error!(
+                    "failed to remove image for deployment {}: {error}",
+                    self.deployment
+                );
+            }
+        }
+    }
+
+    /// prezel.json errors make the build fail instead of silently falling back to the defaults
+    #[tracing::instrument]
+    async fn load_config(
+        &self,
+        path: &Path,
+        hooks: &dyn DeploymentHooks,
+    ) -> anyhow::Result<DeploymentConfig> {
+        let content = DeploymentConfig::read_from_context(path).await?;
+        let config = DeploymentConfig::parse(&content)?;
+        *self.settings.write().unwrap() = config.get_proxy_settings(self.default_branch);
+        hooks.on_config_read(&content).await;
+        Ok(config)
     }
 
+    /// returns the app root inside the downloaded repo
     #[tracing::instrument]
-    async fn build_context(&self, path: &Path) -> anyhow::Result<PathBuf> {
+    async fn download(&self, path: &Path) -> anyhow::Result<PathBuf> {
         self.github
             .download_commit(self.repo_id, &self.sha, &path)
             .await?;
         ensure!(path.exists());
+        Ok(path.join(&self.root))
+    }
 
-        let inner_path = path.join(&self.root);
+    fn get_build_args(&self, config: &BuildConfig) -> EnvVars {
+        self.build_args.clone() + config.args.clone().into()
+    }
 
-        if !inner_path.join("Dockerfile").exists() {
-            self.create_dockerfile_with_nixpacks(&inner_path).await?;
+    /// every secret can be mounted on its own, and all of them together as a sourceable file
+    fn get_build_secrets(&self, config: &BuildConfig) -> EnvVars {
+        let secrets = if config.database_env {
+            self.build_secrets.clone() + self.db_env.clone()
+        } else {
+            self.build_secrets.clone()
+        };
+        if secrets.is_empty() {
+            return secrets;
         }
+        let env_file = get_env_file(&secrets);
+        secrets + [(ENV_SECRET, env_file.as_str())].as_ref().into()
+    }
 
-        Ok(inner_path)
+    /// returns the build context and the path of the Dockerfile inside it
+    #[tracing::instrument]
+    async fn build_context(
+        &self,
+        repo: &Path,
+        root: &Path,
+        config: &BuildConfig,
+    ) -> anyhow::Result<(PathBuf, String)> {
+        let context = match &config.context {
+            Some(context) => resolve_path(repo, root, context)?,
+            None => root.to_owned(),
+        };
+        ensure!(context.is_dir(), "Build context not found");
+
+        let dockerfile = match &config.dockerfile {
+            Some(dockerfile) => {
+                let path = resolve_path(&context, &context, dockerfile)?;
+                ensure!(path.is_file(), "Dockerfile not found at {dockerfile}");
+                path
+            }
+            // a Dockerfile committed in the repo might be a symlink too
+            None if context.join("Dockerfile").exists() => {
+                resolve_path(&context, &context, "Dockerfile")?
+            }
+            None => {
+                self.create_dockerfile_with_nixpacks(&context, config)
+                    .await?;
+                context.join("Dockerfile")
+            }
+        };
+
+        let relative = dockerfile
+            .strip_prefix(&context)?
+            .to_str()
+            .unwrap()
+            .to_owned();
+        Ok((context, relative))
     }
 
     #[tracing::instrument]
-    async fn create_dockerfile_with_nixpacks(&self, inner_path: &Path) -> anyhow::Result<()> {
-        let env_vec: Vec<String> = self.env.clone().into();
+    async fn create_dockerfile_with_nixpacks(
+        &self,
+        inner_path: &Path,
+        config: &BuildConfig,
+    ) -> anyhow::Result<()> {
+        // nixpacks turns these into ARG and ENV lines, so build secrets are sourced by each step
+        let env_vec: Vec<String> = self.get_build_args(config).into();
         create_docker_image(
             inner_path.to_str().unwrap(),
             env_vec.iter().map(String::as_str).collect(),
-            &GeneratePlanOptions::default(),
+            &GeneratePlanOptions {
+                plan: Some(config.nixpacks.get_plan()),
+                config_file: None,
+            },
             &DockerBuilderOptions {
                 out_dir: Some(inner_path.display().to_string()), // TODO: test what happens if I omit this ?
                 // quiet: true,
                 // verbose: false,
                 // name: Some(name.clone()),
                 // print_dockerfile: false,
-                // cache_key: None,
-                // no_cache: true,
+                // shared by every build of the project, nixpacks skips the cache mounts with no_cache
+                cache_key: Some(format!("prezel-{}", self.project)),
+                no_cache: self.no_cache,
                 // inline_cache: false,
                 // platform: vec![],
                 // current_dir: true,
@@ -179,16 +311,74 @@ impl CommitContainer {
         )
         .await?;
 
-        fs::rename(
-            inner_path.join(".nixpacks").join("Dockerfile"),
-            inner_path.join("Dockerfile"),
-        )
-        .await?;
+        let generated = inner_path.join(".nixpacks").join("Dockerfile");
+        if self.get_build_secrets(config).is_empty() {
+            fs::rename(generated, inner_path.join("Dockerfile")).await?;
+        } else {
+            let content = fs::read_to_string(&generated).await?;
+            fs::write(inner_path.join("Dockerfile"), source_env_secret(&content)).await?;
+        }
 
         Ok(())
     }
 }
 
+const ENV_SECRET: &str = "prezel-env";
+
+/// single quoted `export` lines, which are safe to source whatever the values contain
+fn get_env_file(secrets: &EnvVars) -> String {
+    let mut lines: Vec<String> = HashMap::from(secrets.clone())
+        .into_iter()
+        .map(|(name, value)| format!("export {name}='{}'\n", value.replace('\'', r"'\''")))
+        .collect();
+    lines.sort();
+    lines.concat()
+}
+
+/// mounts the env secret in every RUN step of a Dockerfile and sources it before the command,
+/// keeping the mounts nixpacks adds for its cache directories
+fn source_env_secret(dockerfile: &str) -> String {
+    let secret_mount = format!("--mount=type=secret,id={ENV_SECRET}");
+    let lines = dockerfile.lines().map(|line| {
+        let Some(command) = line.trim_start().strip_prefix("RUN ") else {
+            return line.to_owned();
+        };
+        let mut mounts = vec![secret_mount.as_str()];
+        let mut command = command.trim_start();
+        while command.starts_with("--mount=") {
+            let (mount, rest) = command.split_once(' ').unwrap_or((command, ""));
+            mounts.push(mount);
+            command = rest.trim_start();
+        }
+        let mounts = mounts.join(" ");
+        format!("RUN {mounts} . /run/secrets/{ENV_SECRET} && {command}")
+    });
+    lines.collect::<Vec<_>>().join("\n")
+}
+
+/// joins a path from prezel.json to `base`, making sure the result exists and stays inside
+/// `boundary`, even after following symlinks
+fn resolve_path(boundary: &Path, base: &Path, relative: &str) -> anyhow::Result<PathBuf> {
+    let mut resolved = base.to_owned();
+    for component in Path::new(relative).components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => bail!("{relative} is not a relative path"),
+        }
+    }
+    let outside = || anyhow!("{relative} points outside of the repository");
+    ensure!(resolved.starts_with(boundary), outside());
+    let canonical = resolved
+        .canonicalize()
+        .map_err(|_| anyhow!("{relative} not found"))?;
+    ensure!(canonical.starts_with(boundary.canonicalize()?), outside());
+    Ok(resolved)
+}
+
 impl ContainerSetup for CommitContainer {
     fn setup_db<'a>(
         &'a self,
@@ -201,4 +391,60 @@ impl ContainerSetup for CommitContainer {
     ) -> Pin<Box<dyn Future<Output = anyhow::Result<String>> + Send + 'a>> {
         Box::pin(async move { self.build(hooks).await })
     }
+    fn cleanup<'a>(&'a self) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(self.cleanup())
+    }
+}
+
+#[cfg(test)]
+mod commit_tests {
+    use std::{fs, os::unix::fs::symlink};
+
+    use crate::env::EnvVars;
+
+    use super::{get_env_file, resolve_path, source_env_secret};
+
+    #[test]
+    fn test_resolve_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = &dir.path().join("repo");
+        let root = repo.join("apps/web");
+        fs::create_dir_all(root.join("docker")).unwrap();
+        fs::create_dir(dir.path().join("repo-other")).unwrap();
+        fs::write(root.join("docker/Dockerfile"), "FROM node").unwrap();
+        fs::write(dir.path().join("secret"), "").unwrap();
+        symlink(dir.path().join("secret"), root.join("Dockerfile")).unwrap();
+        symlink(repo.join("apps"), root.join("apps")).unwrap();
+
+        assert_eq!(resolve_path(repo, &root, "..").unwrap(), repo.join("apps"));
+        assert_eq!(
+            resolve_path(repo, &root, "./docker/Dockerfile").unwrap(),
+            root.join("docker/Dockerfile")
+        );
+        assert!(resolve_path(repo, &root, "apps").is_ok());
+        assert!(resolve_path(repo, &root, "missing").is_err());
+        assert!(resolve_path(repo, &root, "../../..").is_err());
+        assert!(resolve_path(repo, &root, "../../../repo-other").is_err());
+        assert!(resolve_path(repo, &root, "Dockerfile").is_err());
+    }
+
+    #[test]
+    fn test_env_file() {
+        let secrets = EnvVars::new(&[("TOKEN", "it's $HOME"), ("API_KEY", "abc")]);
+        assert_eq!(
+            get_env_file(&secrets),
+            "export API_KEY='abc'\nexport TOKEN='it'\\''s $HOME'\n"
+        );
+    }
+
+    #[test]
+    fn test_source_env_secret() {
+        let dockerfile = "FROM node\nRUN  npm ci\nRUN --mount=type=cache,id=key-npm,target=/root/.npm npm run build";
+        assert_eq!(
+            source_env_secret(dockerfile),
+            "FROM node\n\
+            RUN --mount=type=secret,id=prezel-env . /run/secrets/prezel-env && npm ci\n\
+            RUN --mount=type=secret,id=prezel-env --mount=type=cache,id=key-npm,target=/root/.npm . /run/secrets/prezel-env && npm run build"
+        );
+    }
 }
@@ -1,5 +1,6 @@
 use anyhow::{anyhow, bail};
 use async_trait::async_trait;
+use futures::future::{AbortHandle, Abortable};
 use std::{
     fmt,
     future::Future,
@@ -7,7 +8,7 @@ use std::{
     ops::Deref,
     path::PathBuf,
     pin::{pin, Pin},
-    sync::Arc,
+    sync::{Arc, Mutex as SyncMutex, RwLock as SyncRwLock, Weak},
     time::{Duration, Instant},
 };
 use tokio::{sync::RwLock, time::sleep};
@@ -19,11 +20,15 @@ use crate::{
     deployments::worker::WorkerHandle,
     docker::{
         build_dockerfile, create_container, get_bollard_container_ipv4,
-        get_container_execution_logs, pull_image, run_container, DockerLog,
+        get_container_execution_logs, pull_image, run_container, wait_for_container_exit,
+        DockerLog,
     },
     env::EnvVars,
     hooks::DeploymentHooks,
     listener::{Access, Listener},
+    metrics::metrics,
+    redact::Redactor,
+    routing::RoutingRules,
     sqlite_db::SqliteDbSetup,
     utils::now,
 };
@@ -39,8 +44,19 @@ pub(crate) struct ContainerConfig {
     pub(crate) command: Option<String>, // TODO: review if I am using this
     pub(crate) initial_status: ContainerStatus,
     pub(crate) result: Option<BuildResult>,
+    pub(crate) redactor: Redactor,
 }
 
+/// Settings the proxy needs to serve the container. For commit containers they come from
+/// prezel.json, so they are only known after reading the build context
+#[derive(Debug, Default)]
+pub(crate) struct ProxySettings {
+    pub(crate) public: bool,
+    pub(crate) routes: Arc<RoutingRules>,
+}
+
+pub(crate) type SharedProxySettings = Arc<SyncRwLock<ProxySettings>>;
+
 // pub(crate) type ContextBuilderOutput =
 //     Pin<Box<dyn Future<Output = anyhow::Result<PathBuf>> + Send>>;
 // pub(crate) type FileSystemOutput = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
@@ -53,17 +69,17 @@ pub(crate) trait ContainerSetup: 'static + Send + Sync + fmt::Debug {
         &'a self,
         hooks: &'a Box<dyn DeploymentHooks>,
     ) -> Pin<Box<dyn Future<Output = anyhow::Result<String>> + Send + 'a>>;
+    /// removes what a cancelled or timed out build might have left behind
+    fn cleanup<'a>(&'a self) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
 }
 
 #[derive(Debug, Clone)]
 pub(crate) enum ContainerStatus {
-    // FIXME ideally I would save the image in the db as well, since anyways there is no guarantee
-    // the image pointed by StandBy {image} exists
-    // I could store the image id in case the build was successful
-    // if there is build_start and build_end but not image id, I can assume the build failed
-    // To be fair, if I detect an image has been removed, I should change the status to be None...
-    /// this means the container was previously built successfully but the image is not known anymore
-    Built,
+    /// this means the container was previously built successfully. The image is only known if it
+    /// was stored in the db and still exists, otherwise the container has to be built again
+    Built {
+        image: Option<String>,
+    },
     Queued {
         trigger_access: Option<Instant>,
     },
@@ -86,6 +102,9 @@ pub(crate) enum ContainerStatus {
         last_access: Arc<RwLock<Instant>>,
     },
     Failed,
+    Cancelled,
+    /// a newer deployment for the same branch was queued before this one was built
+    Skipped,
 }
 
 impl ContainerStatus {
@@ -101,12 +120,29 @@ impl ContainerStatus {
     #[tracing::instrument]
     pub(crate) fn to_status(&self) -> Status {
         match self {
-            Self::Built => Status::Built,
+            Self::Built { .. } => Status::Built,
             Self::StandBy { .. } | Self::Starting { .. } => Status::StandBy, // not relevant for the user?
             Self::Building { .. } => Status::Building,
             Self::Queued { .. } => Status::Queued,
             Self::Ready { .. } => Status::Ready,
             Self::Failed => Status::Failed,
+            Self::Cancelled => Status::Cancelled,
+            Self::Skipped => Status::Skipped,
+        }
+    }
+
+    /// name of the variant, used as a label in the metrics
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Built { .. } => "built",
+            Self::Queued { .. } => "queued",
+            Self::Building { .. } => "building",
+            Self::StandBy { .. } => "standby",
+            Self::Starting { .. } => "starting",
+            Self::Ready { .. } => "ready",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+            Self::Skipped => "skipped",
         }
     }
 
@@ -119,7 +155,11 @@ impl ContainerStatus {
             | Self::StandBy { db_setup, .. }
             | Self::Ready { db_setup, .. }
             | Self::Starting { db_setup, .. } => db_setup.clone(),
-            Self::Queued { .. } | Self::Built | Self::Failed => None,
+            Self::Queued { .. }
+            | Self::Built { .. }
+            | Self::Failed
+            | Self::Cancelled
+            | Self::Skipped => None,
         }
     }
 }
@@ -140,8 +180,23 @@ pub(crate) struct Container {
     config: ContainerConfig,
     hooks: Box<dyn DeploymentHooks>,
     pub(crate) logging_deployment_id: Option<NanoId>,
-    pub(crate) public: bool,
+    pub(crate) settings: SharedProxySettings,
     build_queue: WorkerHandle,
+    /// only set while a build is running
+    build_abort: SyncMutex<Option<AbortHandle>>,
+    /// result recorded for an aborted build, either cancelled or skipped
+    abort_result: SyncMutex<Option<BuildResult>>,
+    /// watches the running container, cancelled when the deployment is dropped so containers
+    /// stopped by prezel are not reported as crashes
+    exit_watcher: SyncMutex<Option<AbortHandle>>,
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        if let Some(watcher) = self.exit_watcher.lock().unwrap().take() {
+            watcher.abort();
+        }
+    }
 }
 
 impl Container {
@@ -152,7 +207,7 @@ impl Container {
         config: ContainerConfig,
         build_queue: WorkerHandle,
         logging_deployment_id: Option<NanoId>,
-        public: bool,
+        settings: SharedProxySettings,
         hooks: impl DeploymentHooks,
     ) -> Self {
         Self {
@@ -162,8 +217,11 @@ impl Container {
             config,
             hooks: Box::new(hooks),
             logging_deployment_id,
-            public,
+            settings,
             build_queue,
+            build_abort: Default::default(),
+            abort_result: Default::default(),
+            exit_watcher: Default::default(),
         }
     }
 
@@ -176,7 +234,12 @@ impl Container {
     #[tracing::instrument]
     pub(crate) async fn get_logs(&self) -> Box<dyn Iterator<Item = DockerLog>> {
         if let Some(container) = self.get_container_id().await {
-            Box::new(get_container_execution_logs(&container).await)
+            let redactor = self.config.redactor.clone();
+            let logs = get_container_execution_logs(&container).await;
+            Box::new(logs.map(move |mut log| {
+                log.message = redactor.redact(&log.message);
+                log
+            }))
         } else {
             Box::new(std::iter::empty())
         }
@@ -188,15 +251,57 @@ impl Container {
         *self.status.write().await = ContainerStatus::Queued {
             trigger_access: None,
         };
+        self.hooks.on_queued().await;
     }
 
     // FIXME: this i pointless now, just a thin wrapper
     #[tracing::instrument]
-    pub(crate) async fn setup_as_standby(&self) -> anyhow::Result<()> {
-        self.build().await?;
+    pub(crate) async fn setup_as_standby(&self, timeout: Duration) -> anyhow::Result<()> {
+        self.build(timeout).await?;
         Ok(())
     }
 
+    /// Cancels the build if it is queued or running. Returns false if there was nothing to cancel
+    #[tracing::instrument]
+    pub(crate) async fn cancel_build(&self) -> bool {
+        self.stop_build(BuildResult::Cancelled, true).await
+    }
+
+    /// Skips the build if it is queued, or also if it is running when `include_running` is set
+    #[tracing::instrument]
+    pub(crate) async fn skip_build(&self, include_running: bool) -> bool {
+        self.stop_build(BuildResult::Skipped, include_running).await
+    }
+
+    async fn stop_build(&self, result: BuildResult, include_running: bool) -> bool {
+        let mut status = self.status.write().await;
+        let running = {
+            let mut build_abort = self.build_abort.lock().unwrap();
+            if !include_running {
+                build_abort.is_some().then_some(false)
+            } else if let Some(abort) = build_abort.take() {
+                // the build itself takes care of setting the new status once it sees the
+                // handle is gone, even if it finished before being aborted
+                *self.abort_result.lock().unwrap() = Some(result);
+                abort.abort();
+                Some(true)
+            } else {
+                None
+            }
+        };
+        if let Some(stopped) = running {
+            stopped
+        } else if let ContainerStatus::Queued { .. } = status.deref() {
+            *status = get_stopped_status(result);
+            drop(status);
+            *self.result.write().await = Some(result);
+            self.hooks.on_build_stopped(result).await;
+            true
+        } else {
+            false
+        }
+    }
+
     #[tracing::instrument]
     pub(crate) async fn downgrade_if_unused(&self) {
         let new_status = if let ContainerStatus::Ready {
@@ -222,24 +327,90 @@ impl Container {
 
         if let Some(new_status) = new_status {
             *self.status.write().await = new_status;
+            self.hooks.on_container_downgraded().await;
+        }
+    }
+
+    /// moves a Built container to StandBy using the image from its last build, if it is known
+    #[tracing::instrument]
+    pub(crate) async fn restore(&self) -> anyhow::Result<()> {
+        let mut status = self.status.write().await;
+        if let ContainerStatus::Built { image: Some(image) } = status.clone() {
+            let db_setup = self.setup.setup_db().await?;
+            *status = ContainerStatus::StandBy { image, db_setup };
         }
+        Ok(())
     }
 
     #[tracing::instrument]
-    async fn build(&self) -> anyhow::Result<()> {
+    async fn build(&self, timeout: Duration) -> anyhow::Result<()> {
         // FIXME: I think there might be a race condition here where the container build is started twice
         // at the same time...
+        if !matches!(
+            self.status.read().await.deref(),
+            ContainerStatus::Queued { .. }
+        ) {
+            // cancelled or skipped while waiting for the worker
+            return Ok(());
+        }
+
         self.hooks.on_build_started().await;
 
         let db_setup = self.setup.setup_db().await?;
 
-        *self.status.write().await = ContainerStatus::Building {
-            db_setup: db_setup.clone(),
-        };
+        let (abort, registration) = AbortHandle::new_pair();
+        {
+            let mut status = self.status.write().await;
+            if !matches!(status.deref(), ContainerStatus::Queued { .. }) {
+                // cancelled or skipped while setting up the db
+                return Ok(());
+            }
+            *status = ContainerStatus::Building {
+                db_setup: db_setup.clone(),
+            };
+            *self.build_abort.lock().unwrap() = Some(abort);
+            *self.abort_result.lock().unwrap() = None;
+        }
 
-        match self.setup.build(&self.hooks).await {
+        let build_start = Instant::now();
+        let build = Abortable::new(self.setup.build(&self.hooks), registration);
+        let result = tokio::time::timeout(timeout, build).await;
+        // if the handle is gone the build was stopped, maybe right after it finished
+        let stopped = self.build_abort.lock().unwrap().take().is_none();
+        let result = match result {
+            Ok(Ok(result)) if !stopped => result,
+            Err(_) if !stopped => {
+                self.setup.cleanup().await;
+                Err(anyhow!(
+                    "Build timed out after {} seconds",
+                    timeout.as_secs()
+                ))
+            }
+            _ => {
+                let result = self
+                    .abort_result
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .unwrap_or(BuildResult::Cancelled);
+                // dropping the build already removed its context and stopped the docker build,
+                // an image built right before the stop is just not used
+                self.setup.cleanup().await;
+                let message = match result {
+                    BuildResult::Skipped => "Build skipped in favor of a newer commit",
+                    _ => "Build cancelled",
+                };
+                self.hooks.on_build_log(message, true).await;
+                self.hooks.on_build_stopped(result).await;
+                *self.status.write().await = get_stopped_status(result);
+                *self.result.write().await = Some(result);
+                return Ok(());
+            }
+        };
+        metrics().record_build(result.is_ok(), build_start.elapsed());
+        match result {
             Ok(image) => {
-                self.hooks.on_build_finished().await;
+                self.hooks.on_build_finished(&image).await;
                 *self.result.write().await = Some(BuildResult::Built);
                 *self.status.write().await = ContainerStatus::StandBy { image, db_setup };
             }
@@ -255,7 +426,7 @@ impl Container {
     }
 
     #[tracing::instrument]
-    pub(crate) async fn start(&self) -> anyhow::Result<SocketAddrV4> {
+    pub(crate) async fn start(self: &Arc<Self>) -> anyhow::Result<SocketAddrV4> {
         let (owned_start, image, db_setup) = {
             let mut current = self.status.write().await;
             if let ContainerStatus::StandBy { image, db_setup } = current.clone() {
@@ -274,6 +445,8 @@ impl Container {
         };
 
         if owned_start {
+            self.hooks.on_container_starting().await;
+            let start = Instant::now();
             if self.config.pull {
                 pull_image(&image).await;
             }
@@ -320,13 +493,25 @@ impl Container {
             //     bail!("Container start timed out");
             // }
 
+            self.hooks.on_container_started(&container).await;
             *self.status.write().await = ContainerStatus::Ready {
                 image: image.clone(),
                 db_setup,
-                container,
+                container: container.clone(),
                 socket,
                 last_access: RwLock::new(Instant::now()).into(),
             };
+            metrics().record_cold_start(start.elapsed());
+
+            let (watcher, registration) = AbortHandle::new_pair();
+            let this = Arc::downgrade(self);
+            tokio::spawn(Abortable::new(
+                Self::watch_exit(this, container),
+                registration,
+            ));
+            if let Some(previous) = self.exit_watcher.lock().unwrap().replace(watcher) {
+                previous.abort();
+            }
 
             Ok(socket)
         } else {
@@ -339,12 +524,50 @@ impl Container {
             }
         }
     }
+
+    /// containers exiting while still Ready were not stopped by prezel, so they are considered
+    /// crashed and go back to StandBy to be started again by the next request. The deployment
+    /// is only weakly referenced, once it is dropped its container is stopped by prezel
+    async fn watch_exit(this: Weak<Self>, container: String) {
+        let Some(exit_code) = wait_for_container_exit(&container).await else {
+            return;
+        };
+        let Some(this) = this.upgrade() else {
+            return;
+        };
+        let crashed = {
+            let mut status = this.status.write().await;
+            match status.clone() {
+                ContainerStatus::Ready {
+                    image,
+                    db_setup,
+                    container: current,
+                    ..
+                } if current == container => {
+                    *status = ContainerStatus::StandBy { image, db_setup };
+                    true
+                }
+                _ => false,
+            }
+        };
+        if crashed {
+            error!("container {container} exited unexpectedly with code {exit_code}");
+            this.hooks.on_container_crashed(Some(exit_code)).await;
+        }
+    }
 }
 
 #[async_trait]
 impl Listener for Arc<Container> {
     fn is_public(&self) -> bool {
-        self.public
+        self.settings.read().unwrap().public
+    }
+
+    async fn container_id(&self) -> Option<String> {
+        match self.status.read().await.deref() {
+            ContainerStatus::Ready { container, .. } => Some(container.clone()),
+            _ => None,
+        }
     }
 
     #[tracing::instrument]
@@ -388,10 +611,16 @@ impl Listener for Arc<Container> {
                         let socket = self.start().await?;
                         Ok(Access::Socket(socket))
                     }
-                    ContainerStatus::Built => {
+                    ContainerStatus::Built { image: Some(_) } => {
+                        self.restore().await?;
+                        let socket = self.start().await?;
+                        Ok(Access::Socket(socket))
+                    }
+                    ContainerStatus::Built { image: None } => {
                         *self.status.write().await = ContainerStatus::Queued {
                             trigger_access: Some(Instant::now()),
                         };
+                        self.hooks.on_queued().await;
                         self.build_queue.trigger();
                         Ok(Access::Loading)
                     }
@@ -401,12 +630,25 @@ impl Listener for Arc<Container> {
                     ContainerStatus::Failed => {
                         bail!("container failed to build")
                     }
+                    ContainerStatus::Cancelled => {
+                        bail!("container build was cancelled")
+                    }
+                    ContainerStatus::Skipped => {
+                        bail!("container build was skipped in favor of a newer commit")
+                    }
                 }
             }
         }
     }
 }
 
+fn get_stopped_status(result: BuildResult) -> ContainerStatus {
+    match result {
+        BuildResult::Skipped => ContainerStatus::Skipped,
+        _ => ContainerStatus::Cancelled,
+    }
+}
+
 // FIXME: this might fail, especially for some API server with no / route
 // there has to be another way
 #[tracing::instrument]
@@ -1,8 +1,13 @@
-use std::path::PathBuf;
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
 
 use crate::{deployments::worker::WorkerHandle, hooks::NoopHooks, sqlite_db::SqliteDbSetup};
 
-use super::{BuildResult, Container, ContainerConfig, ContainerSetup, ContainerStatus};
+use super::{
+    BuildResult, Container, ContainerConfig, ContainerSetup, ContainerStatus, ProxySettings,
+};
 
 const VERSION: &str = "0.24.28";
 
@@ -13,6 +18,10 @@ impl SqldContainer {
     #[tracing::instrument]
     pub(crate) fn new(db_folder: PathBuf, key: &str, build_queue: WorkerHandle) -> Container {
         let builder = Self {};
+        let settings = ProxySettings {
+            public: true,
+            routes: Default::default(),
+        };
         let db_path = db_folder.display().to_string();
         Container::new(
             builder,
@@ -32,10 +41,11 @@ impl SqldContainer {
                 },
                 command: None,
                 result: Some(BuildResult::Built),
+                redactor: Default::default(),
             },
             build_queue,
             None,
-            true,
+            Arc::new(RwLock::new(settings)),
             NoopHooks,
         )
     }
@@ -57,4 +67,9 @@ impl ContainerSetup for SqldContainer {
     {
         todo!()
     }
+    fn cleanup<'a>(
+        &'a self,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'a>> {
+        Box::pin(async {})
+    }
 }
@@ -8,12 +8,21 @@ use sqlx::{sqlite::SqlitePool, FromRow, Pool, Sqlite};
 use tracing::info;
 use utoipa::ToSchema;
 
+use encryption::EnvCipher;
+
 use crate::{
+    analytics::BucketStats,
+    ip_filter::{parse_cidr, Environment, IpAction, IpRule},
     label::Label,
+    log_drain::LogDrain,
+    notifications::{NotificationChannel, NotificationDelivery},
     paths::get_instance_db_path,
+    protection::BasicAuth,
+    rate_limit::RateLimits,
     utils::{now, PlusHttps, LOWERCASE_PLUS_NUMBERS},
 };
 
+pub(crate) mod encryption;
 pub(crate) mod nano_id;
 
 #[derive(sqlx::Type, PartialEq, Clone, Copy, Debug)]
@@ -21,6 +30,40 @@ pub(crate) mod nano_id;
 pub(crate) enum BuildResult {
     Built,
     Failed,
+    Cancelled,
+    /// superseded by a newer deployment for the same branch before being built
+    Skipped,
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, PartialEq, Clone, Copy, Debug, ToSchema)]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DeploymentEventKind {
+    Queued,
+    BuildStarted,
+    BuildFinished,
+    BuildFailed,
+    BuildCancelled,
+    BuildSkipped,
+    ContainerStarting,
+    ContainerStarted,
+    /// the container was stopped after not receiving requests for a while
+    Downgraded,
+    /// the container exited without prezel stopping it
+    Crashed,
+    PromotedToProd,
+    /// production moved back to this deployment from a newer one
+    Rollback,
+    Deleted,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub(crate) struct DeploymentEvent {
+    pub(crate) id: i64,
+    pub(crate) event: DeploymentEventKind,
+    /// epoch in milliseconds
+    pub(crate) timestamp: i64,
+    pub(crate) detail: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -31,6 +74,14 @@ struct PlainProject {
     pub(crate) created: i64,
     pub(crate) root: String,
     pub(crate) prod_id: MaybeNanoId,
+    pub(crate) basic_auth_username: Option<String>,
+    pub(crate) basic_auth_password: Option<String>,
+    pub(crate) rate_limits: Option<String>,
+    pub(crate) log_drains: Option<String>,
+    pub(crate) notification_channels: Option<String>,
+    pub(crate) build_timeout: Option<i64>,
+    pub(crate) cancel_superseded_builds: i64,
+    pub(crate) promoted_deployment: MaybeNanoId,
 }
 
 #[derive(FromRow, Debug)]
@@ -46,6 +97,9 @@ struct PlainDeployment {
     pub(crate) build_started: Option<i64>,
     pub(crate) build_finished: Option<i64>,
     pub(crate) project: NanoId,
+    pub(crate) config: Option<String>,
+    pub(crate) image_id: Option<String>,
+    pub(crate) no_cache: bool,
 }
 
 #[derive(Debug)]
@@ -62,6 +116,12 @@ pub(crate) struct Deployment {
     pub(crate) build_finished: Option<i64>,
     pub(crate) project: NanoId,
     pub(crate) env: Vec<EnvVar>,
+    /// validated content of prezel.json, only available after the first build
+    pub(crate) config: Option<String>,
+    /// docker image of the last successful build, which might have been removed since
+    pub(crate) image_id: Option<String>,
+    /// builds ignore the layers of previous builds
+    pub(crate) no_cache: bool,
 }
 
 impl Deployment {
@@ -70,17 +130,48 @@ impl Deployment {
     }
 }
 
+/// build vars are passed as build args, runtime vars only to the running container
+#[derive(sqlx::Type, Serialize, Deserialize, PartialEq, Clone, Copy, Debug, Default, ToSchema)]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EnvScope {
+    Build,
+    Runtime,
+    #[default]
+    Both,
+}
+
+impl EnvScope {
+    pub(crate) fn includes(self, phase: EnvScope) -> bool {
+        self == EnvScope::Both || self == phase
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub(crate) struct EditedEnvVar {
     pub(crate) name: String,
     pub(crate) value: String,
     pub(crate) edited: i64,
+    #[serde(default)]
+    pub(crate) scope: EnvScope,
+    #[serde(default)]
+    pub(crate) environment: Option<Environment>,
+    #[serde(default)]
+    pub(crate) branch: Option<String>,
 }
 
 #[derive(Deserialize, Debug, ToSchema)]
 pub(crate) struct EnvVar {
     pub(crate) name: String,
     pub(crate) value: String,
+    #[serde(default)]
+    pub(crate) scope: EnvScope,
+    /// the var applies to every environment if not set
+    #[serde(default)]
+    pub(crate) environment: Option<Environment>,
+    /// branch name or pattern using *, only matched against previews
+    #[serde(default)]
+    pub(crate) branch: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -93,6 +184,18 @@ pub(crate) struct Project {
     pub(crate) root: String,
     pub(crate) prod_id: Option<NanoId>,
     pub(crate) custom_domains: Vec<String>,
+    /// if set, private deployments can also be accessed with these credentials
+    pub(crate) basic_auth: Option<BasicAuth>,
+    pub(crate) ip_rules: Vec<IpRule>,
+    pub(crate) rate_limits: RateLimits,
+    pub(crate) log_drains: Vec<LogDrain>,
+    pub(crate) notification_channels: Vec<NotificationChannel>,
+    /// in seconds, the default timeout is used if missing
+    pub(crate) build_timeout: Option<i64>,
+    /// superseded deployments are always skipped if queued, this also stops them if building
+    pub(crate) cancel_superseded_builds: bool,
+    /// last deployment recorded as serving production
+    pub(crate) promoted_deployment: Option<NanoId>,
 }
 
 #[derive(Deserialize, Debug, ToSchema)]
@@ -107,6 +210,9 @@ pub(crate) struct InsertProject {
 pub(crate) struct UpdateProject {
     pub(crate) name: Option<String>,
     custom_domains: Option<Vec<String>>,
+    /// in seconds
+    pub(crate) build_timeout: Option<i64>,
+    pub(crate) cancel_superseded_builds: Option<bool>,
 }
 
 #[derive(FromRow)]
@@ -125,13 +231,16 @@ pub(crate) struct DeploymentWithProject {
 }
 
 impl DeploymentWithProject {
-    pub(crate) fn get_app_base_url(&self, box_domain: &str) -> String {
+    pub(crate) fn get_app_hostname(&self, box_domain: &str) -> String {
         Label::Deployment {
             project: self.project.name.clone(),
             deployment: self.url_id.to_string(),
         }
         .format_hostname(box_domain)
-        .plus_https()
+    }
+
+    pub(crate) fn get_app_base_url(&self, box_domain: &str) -> String {
+        self.get_app_hostname(box_domain).plus_https()
     }
 
     pub(crate) fn get_prod_base_url(&self, box_domain: &str) -> String {
@@ -179,6 +288,88 @@ pub(crate) struct InsertDeployment {
     pub(crate) branch: String,
     pub(crate) default_branch: i64,
     pub(crate) project: NanoId,
+    pub(crate) no_cache: bool,
+}
+
+/// Falls back to the previous secret after rotating it, wrapping the data key with the new one
+async fn load_env_cipher(
+    conn: &Pool<Sqlite>,
+    secret: &str,
+    previous_secret: Option<&str>,
+) -> EnvCipher {
+    let stored = sqlx::query!("select wrapped_key from encryption_keys where id = 1")
+        .fetch_optional(conn)
+        .await
+        .unwrap();
+    let Some(stored) = stored else {
+        let (cipher, wrapped_key) = EnvCipher::generate(secret);
+        let created = now();
+        sqlx::query!(
+            "insert into encryption_keys (id, wrapped_key, created) values (1, ?, ?)",
+            wrapped_key,
+            created
+        )
+        .execute(conn)
+        .await
+        .unwrap();
+        return cipher;
+    };
+    if let Ok(cipher) = EnvCipher::unwrap(secret, &stored.wrapped_key) {
+        return cipher;
+    }
+    let previous_secret = previous_secret
+        .expect("The env encryption key can't be unwrapped, set previous_secret in config.json");
+    let wrapped_key = EnvCipher::rewrap(previous_secret, secret, &stored.wrapped_key)
+        .expect("The env encryption key can't be unwrapped with previous_secret either");
+    sqlx::query!(
+        "update encryption_keys set wrapped_key = ? where id = 1",
+        wrapped_key
+    )
+    .execute(conn)
+    .await
+    .unwrap();
+    info!("env encryption key wrapped with the new secret");
+    EnvCipher::unwrap(secret, &wrapped_key).unwrap()
+}
+
+/// values stored before env encryption was introduced
+async fn encrypt_plain_env(conn: &Pool<Sqlite>, cipher: &EnvCipher) {
+    let env = sqlx::query!(
+        r#"select rowid as "rowid!: i64", project as "project: NanoId", name, value from env where encrypted = 0"#
+    )
+    .fetch_all(conn)
+    .await
+    .unwrap();
+    for record in env {
+        let value = cipher.encrypt(&record.project, &record.name, &record.value);
+        sqlx::query!(
+            "update env set value = ?, encrypted = 1 where rowid = ?",
+            value,
+            record.rowid
+        )
+        .execute(conn)
+        .await
+        .unwrap();
+    }
+    let deployment_env = sqlx::query!(
+        r#"select deployment_env.rowid as "rowid!: i64", deployments.project as "project!: NanoId", name, value
+        from deployment_env join deployments on deployments.id = deployment_env.deployment
+        where encrypted = 0"#
+    )
+    .fetch_all(conn)
+    .await
+    .unwrap();
+    for record in deployment_env {
+        let value = cipher.encrypt(&record.project, &record.name, &record.value);
+        sqlx::query!(
+            "update deployment_env set value = ?, encrypted = 1 where rowid = ?",
+            value,
+            record.rowid
+        )
+        .execute(conn)
+        .await
+        .unwrap();
+    }
 }
 
 fn create_deployment_url_id() -> String {
@@ -188,11 +379,12 @@ fn create_deployment_url_id() -> String {
 #[derive(Clone, Debug)]
 pub(crate) struct Db {
     conn: Pool<Sqlite>, // TODO: put this in a module with db.rs and make this provate
+    env_cipher: Arc<EnvCipher>,
 }
 
 impl Db {
     #[tracing::instrument]
-    pub(crate) async fn setup() -> Self {
+    pub(crate) async fn setup(secret: &str, previous_secret: Option<&str>) -> Self {
         let db_path = get_instance_db_path();
         let db_path_str = db_path.to_str().expect("Path to DB coud not be generated");
 
@@ -213,7 +405,18 @@ impl Db {
             db_path.canonicalize().unwrap().to_str().unwrap()
         );
 
-        Self { conn }
+        let env_cipher = load_env_cipher(&conn, secret, previous_secret).await;
+        encrypt_plain_env(&conn, &env_cipher).await;
+
+        Self {
+            conn,
+            env_cipher: Arc::new(env_cipher),
+        }
+    }
+
+    /// env values are only decrypted right before they are handed to a container
+    pub(crate) fn env_cipher(&self) -> &EnvCipher {
+        &self.env_cipher
     }
 
     // TODO: try to make the manager have access only to the read methods in here
@@ -266,14 +469,61 @@ impl Db {
             .into_iter()
             .map(|record| record.domain)
             .collect();
-        let env = sqlx::query_as!(
-            EditedEnvVar,
-            "select name, value, edited from env where project = ?",
+        let env = sqlx::query!(
+            r#"select name, value, edited, scope as "scope: EnvScope", environment, branch from env where project = ? order by name"#,
             project.id
         )
         .fetch_all(&self.conn)
         .await
-        .unwrap();
+        .unwrap()
+        .into_iter()
+        .map(|record| EditedEnvVar {
+            name: record.name,
+            value: record.value,
+            edited: record.edited,
+            scope: record.scope,
+            environment: record.environment.as_deref().and_then(Environment::from_str),
+            branch: record.branch,
+        })
+        .collect();
+        let ip_rules = sqlx::query!(
+            "select cidr, action, environment from ip_rules where project = ? order by id",
+            project.id
+        )
+        .fetch_all(&self.conn)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|record| {
+            Some(IpRule {
+                cidr: parse_cidr(&record.cidr)?,
+                action: IpAction::from_str(&record.action)?,
+                environment: match record.environment {
+                    Some(environment) => Some(Environment::from_str(&environment)?),
+                    None => None,
+                },
+            })
+        })
+        .collect();
+        let rate_limits = project
+            .rate_limits
+            .and_then(|rate_limits| serde_json::from_str(&rate_limits).ok())
+            .unwrap_or_default();
+        let log_drains = project
+            .log_drains
+            .and_then(|log_drains| serde_json::from_str(&log_drains).ok())
+            .unwrap_or_default();
+        let notification_channels = project
+            .notification_channels
+            .and_then(|channels| serde_json::from_str(&channels).ok())
+            .unwrap_or_default();
+        let basic_auth = project
+            .basic_auth_username
+            .zip(project.basic_auth_password)
+            .map(|(username, password_hash)| BasicAuth {
+                username,
+                password_hash,
+            });
 
         Project {
             id: project.id,
@@ -284,6 +534,14 @@ impl Db {
             root: project.root,
             prod_id: project.prod_id.0,
             custom_domains,
+            basic_auth,
+            ip_rules,
+            rate_limits,
+            log_drains,
+            notification_channels,
+            build_timeout: project.build_timeout,
+            cancel_superseded_builds: project.cancel_superseded_builds != 0,
+            promoted_deployment: project.promoted_deployment.0,
         }
     }
 
@@ -313,11 +571,16 @@ impl Db {
         .unwrap();
         let edited = now();
         for env in env {
+            let environment = env.environment.map(|environment| environment.as_str());
+            let value = self.env_cipher.encrypt(&id, &env.name, &env.value);
             sqlx::query!(
-                "insert into env (name, value, edited, project) values (?, ?, ?, ?)",
+                "insert into env (name, value, edited, scope, environment, branch, project, encrypted) values (?, ?, ?, ?, ?, ?, ?, 1)",
                 env.name,
-                env.value,
+                value,
                 edited,
+                env.scope,
+                environment,
+                env.branch,
                 id,
             )
             .execute(&self.conn)
@@ -333,8 +596,32 @@ impl Db {
         UpdateProject {
             name,
             custom_domains,
+            build_timeout,
+            cancel_superseded_builds,
         }: UpdateProject,
     ) {
+        if let Some(cancel_superseded_builds) = cancel_superseded_builds {
+            sqlx::query!(
+                "update projects set cancel_superseded_builds = ? where id = ?",
+                cancel_superseded_builds,
+                id
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+        }
+
+        if let Some(build_timeout) = build_timeout {
+            sqlx::query!(
+                "update projects set build_timeout = ? where id = ?",
+                build_timeout,
+                id
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+        }
+
         if let Some(name) = name {
             sqlx::query!("update projects set name = ? where id = ?", name, id)
                 .execute(&self.conn)
@@ -362,6 +649,162 @@ impl Db {
         }
     }
 
+    #[tracing::instrument]
+    pub(crate) async fn update_project_basic_auth(
+        &self,
+        id: &NanoId,
+        basic_auth: Option<BasicAuth>,
+    ) {
+        let (username, password) = basic_auth
+            .map(|auth| (auth.username, auth.password_hash))
+            .unzip();
+        sqlx::query!(
+            "update projects set basic_auth_username = ?, basic_auth_password = ? where id = ?",
+            username,
+            password,
+            id
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
+    #[tracing::instrument]
+    pub(crate) async fn update_project_rate_limits(&self, id: &NanoId, rate_limits: &RateLimits) {
+        let rate_limits = serde_json::to_string(rate_limits).unwrap();
+        sqlx::query!(
+            "update projects set rate_limits = ? where id = ?",
+            rate_limits,
+            id
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
+    #[tracing::instrument]
+    pub(crate) async fn update_project_log_drains(&self, id: &NanoId, log_drains: &[LogDrain]) {
+        let log_drains = serde_json::to_string(log_drains).unwrap();
+        sqlx::query!(
+            "update projects set log_drains = ? where id = ?",
+            log_drains,
+            id
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
+    #[tracing::instrument]
+    pub(crate) async fn update_project_notification_channels(
+        &self,
+        id: &NanoId,
+        channels: &[NotificationChannel],
+    ) {
+        let channels = serde_json::to_string(channels).unwrap();
+        sqlx::query!(
+            "update projects set notification_channels = ? where id = ?",
+            channels,
+            id
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
+    #[tracing::instrument]
+    pub(crate) async fn update_project_promoted_deployment(
+        &self,
+        id: &NanoId,
+        deployment: &NanoId,
+    ) {
+        sqlx::query!(
+            "update projects set promoted_deployment = ? where id = ?",
+            deployment,
+            id
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
+    #[tracing::instrument]
+    pub(crate) async fn insert_notification_delivery(
+        &self,
+        project: &NanoId,
+        delivery: &NotificationDelivery,
+    ) {
+        sqlx::query!(
+            "insert into notification_deliveries (event, deployment, channel, url, attempt, timestamp, status, error, project) values (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            delivery.event,
+            delivery.deployment,
+            delivery.channel,
+            delivery.url,
+            delivery.attempt,
+            delivery.timestamp,
+            delivery.status,
+            delivery.error,
+            project
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
+    /// most recent first
+    #[tracing::instrument]
+    pub(crate) async fn get_notification_deliveries(
+        &self,
+        project: &NanoId,
+        limit: i64,
+    ) -> Vec<NotificationDelivery> {
+        sqlx::query_as!(
+            NotificationDelivery,
+            "select id, event, deployment, channel, url, attempt, timestamp, status, error from notification_deliveries where project = ? order by timestamp desc, id desc limit ?",
+            project,
+            limit
+        )
+        .fetch_all(&self.conn)
+        .await
+        .unwrap()
+    }
+
+    #[tracing::instrument]
+    pub(crate) async fn delete_notification_deliveries_before(&self, timestamp: i64) {
+        sqlx::query!(
+            "delete from notification_deliveries where timestamp < ?",
+            timestamp
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
+    #[tracing::instrument]
+    pub(crate) async fn update_project_ip_rules(&self, id: &NanoId, rules: &[IpRule]) {
+        let mut tx = self.conn.begin().await.unwrap();
+        sqlx::query!("delete from ip_rules where project = ?", id)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        for rule in rules {
+            let cidr = rule.cidr.to_string();
+            let action = rule.action.as_str();
+            let environment = rule.environment.map(|environment| environment.as_str());
+            sqlx::query!(
+                "insert into ip_rules (cidr, action, environment, project) values (?, ?, ?, ?)",
+                cidr,
+                action,
+                environment,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+        tx.commit().await.unwrap();
+    }
+
     #[tracing::instrument]
     pub(crate) async fn delete_project(&self, id: &NanoId) {
         sqlx::query!("delete from projects where id = ?", id)
@@ -371,28 +814,53 @@ impl Db {
     }
 
     #[tracing::instrument]
-    pub(crate) async fn upsert_env(&self, project: &NanoId, name: &str, value: &str) {
+    pub(crate) async fn upsert_env(&self, project: &NanoId, env: &EnvVar) {
         let edited = now();
+        let environment = env.environment.map(|environment| environment.as_str());
+        let value = self.env_cipher.encrypt(project, &env.name, &env.value);
+        let mut tx = self.conn.begin().await.unwrap();
         sqlx::query!(
-            "insert into env (project, name, value, edited) values (?, ?, ?, ?) on conflict (name, project) do update set value=?, edited=?",
+            "delete from env where project = ? and name = ? and environment is ? and branch is ?",
             project,
-            name,
-            value,
-            edited,
+            env.name,
+            environment,
+            env.branch,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        sqlx::query!(
+            "insert into env (project, name, value, edited, scope, environment, branch, encrypted) values (?, ?, ?, ?, ?, ?, ?, 1)",
+            project,
+            env.name,
             value,
             edited,
+            env.scope,
+            environment,
+            env.branch,
         )
-        .execute(&self.conn)
+        .execute(&mut *tx)
         .await
         .unwrap();
+        tx.commit().await.unwrap();
     }
 
+    /// only deletes the value for the given environment and branch
     #[tracing::instrument]
-    pub(crate) async fn delete_env(&self, project: &NanoId, name: &str) {
+    pub(crate) async fn delete_env(
+        &self,
+        project: &NanoId,
+        name: &str,
+        environment: Option<Environment>,
+        branch: Option<&str>,
+    ) {
+        let environment = environment.map(|environment| environment.as_str());
         sqlx::query!(
-            "delete from env where project = ? and name = ?",
+            "delete from env where project = ? and name = ? and environment is ? and branch is ?",
             project,
-            name
+            name,
+            environment,
+            branch,
         )
         .execute(&self.conn)
         .await
@@ -403,7 +871,7 @@ impl Db {
     pub(crate) async fn get_deployment(&self, deployment: &NanoId) -> Option<Deployment> {
         let plain_deployment = sqlx::query_as!(
             PlainDeployment,
-            r#"select id, slug, timestamp, created, sha, branch, default_branch, result as "result: BuildResult", build_started, build_finished,project from deployments where id = ? and deleted is null"#,
+            r#"select id, slug, timestamp, created, sha, branch, default_branch, result as "result: BuildResult", build_started, build_finished, project, config, image_id, no_cache as "no_cache: bool" from deployments where id = ? and deleted is null"#,
             deployment
         )
         .fetch_optional(&self.conn)
@@ -413,12 +881,120 @@ impl Db {
         Some(self.append_extra_deployment_info(plain_deployment).await)
     }
 
+    #[tracing::instrument(skip(stats))]
+    pub(crate) async fn merge_analytics_bucket(
+        &self,
+        project: &NanoId,
+        deployment: &NanoId,
+        start: i64,
+        stats: &BucketStats,
+    ) {
+        let mut tx = self.conn.begin().await.unwrap();
+        let existing = sqlx::query!(
+            "select * from request_analytics where deployment = ? and start = ?",
+            deployment,
+            start
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .unwrap();
+
+        let mut merged = existing
+            .map(|record| BucketStats {
+                requests: record.requests,
+                status: [
+                    record.status_1xx,
+                    record.status_2xx,
+                    record.status_3xx,
+                    record.status_4xx,
+                    record.status_5xx,
+                ],
+                latency: serde_json::from_str(&record.latency).unwrap_or_default(),
+                paths: serde_json::from_str(&record.paths).unwrap_or_default(),
+                referrers: serde_json::from_str(&record.referrers).unwrap_or_default(),
+            })
+            .unwrap_or_default();
+        merged.merge(stats);
+        merged.truncate();
+
+        let [status_1xx, status_2xx, status_3xx, status_4xx, status_5xx] = merged.status;
+        let latency = serde_json::to_string(&merged.latency).unwrap();
+        let paths = serde_json::to_string(&merged.paths).unwrap();
+        let referrers = serde_json::to_string(&merged.referrers).unwrap();
+        sqlx::query!(
+            "insert or replace into request_analytics (deployment, start, requests, status_1xx, status_2xx, status_3xx, status_4xx, status_5xx, latency, paths, referrers, project)
+            values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            deployment,
+            start,
+            merged.requests,
+            status_1xx,
+            status_2xx,
+            status_3xx,
+            status_4xx,
+            status_5xx,
+            latency,
+            paths,
+            referrers,
+            project
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+    }
+
+    /// returns the start and stats of the buckets since the given epoch in seconds
+    #[tracing::instrument]
+    pub(crate) async fn get_analytics_buckets(
+        &self,
+        project: &NanoId,
+        deployment: Option<&NanoId>,
+        since: i64,
+    ) -> Vec<(i64, BucketStats)> {
+        sqlx::query!(
+            "select * from request_analytics where project = ? and (? is null or deployment = ?) and start >= ?",
+            project,
+            deployment,
+            deployment,
+            since
+        )
+        .fetch_all(&self.conn)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|record| {
+            let stats = BucketStats {
+                requests: record.requests,
+                status: [
+                    record.status_1xx,
+                    record.status_2xx,
+                    record.status_3xx,
+                    record.status_4xx,
+                    record.status_5xx,
+                ],
+                latency: serde_json::from_str(&record.latency).unwrap_or_default(),
+                paths: serde_json::from_str(&record.paths).unwrap_or_default(),
+                referrers: serde_json::from_str(&record.referrers).unwrap_or_default(),
+            };
+            (record.start, stats)
+        })
+        .collect()
+    }
+
+    #[tracing::instrument]
+    pub(crate) async fn delete_analytics_before(&self, before: i64) {
+        sqlx::query!("delete from request_analytics where start < ?", before)
+            .execute(&self.conn)
+            .await
+            .unwrap();
+    }
+
     // TODO: just return stream here?
     #[tracing::instrument]
     pub(crate) async fn get_deployments(&self) -> Vec<Deployment> {
         let deployments = sqlx::query_as!(
             PlainDeployment,
-            r#"select id, slug, timestamp, created, sha, branch, default_branch, result as "result: BuildResult", build_started, build_finished, project from deployments where deleted is null"#
+            r#"select id, slug, timestamp, created, sha, branch, default_branch, result as "result: BuildResult", build_started, build_finished, project, config, image_id, no_cache as "no_cache: bool" from deployments where deleted is null"#
         )
         .fetch_all(&self.conn)
         .await
@@ -432,14 +1008,23 @@ impl Db {
 
     #[tracing::instrument]
     async fn append_extra_deployment_info(&self, deployment: PlainDeployment) -> Deployment {
-        let env = sqlx::query_as!(
-            EnvVar,
-            "select name, value from deployment_env where deployment = ?",
+        // the env of a deployment is already resolved for its environment and branch
+        let env = sqlx::query!(
+            r#"select name, value, scope as "scope: EnvScope" from deployment_env where deployment = ?"#,
             deployment.id
         )
         .fetch_all(&self.conn)
         .await
-        .unwrap();
+        .unwrap()
+        .into_iter()
+        .map(|record| EnvVar {
+            name: record.name,
+            value: record.value,
+            scope: record.scope,
+            environment: None,
+            branch: None,
+        })
+        .collect();
 
         Deployment {
             id: deployment.id,
@@ -454,6 +1039,9 @@ impl Db {
             build_finished: deployment.build_finished,
             project: deployment.project,
             env,
+            config: deployment.config,
+            image_id: deployment.image_id,
+            no_cache: deployment.no_cache,
         }
     }
 
@@ -463,6 +1051,41 @@ impl Db {
             .execute(&self.conn)
             .await
             .unwrap();
+        self.insert_deployment_event(id, DeploymentEventKind::Deleted, None)
+            .await;
+    }
+
+    #[tracing::instrument]
+    pub(crate) async fn insert_deployment_event(
+        &self,
+        deployment: &NanoId,
+        event: DeploymentEventKind,
+        detail: Option<&str>,
+    ) {
+        let timestamp = now();
+        sqlx::query!(
+            "insert into deployment_events (event, timestamp, detail, deployment) values (?, ?, ?, ?)",
+            event,
+            timestamp,
+            detail,
+            deployment
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
+    /// oldest first, also available for deleted deployments
+    #[tracing::instrument]
+    pub(crate) async fn get_deployment_events(&self, deployment: &NanoId) -> Vec<DeploymentEvent> {
+        sqlx::query_as!(
+            DeploymentEvent,
+            r#"select id, event as "event: DeploymentEventKind", timestamp, detail from deployment_events where deployment = ? order by timestamp, id"#,
+            deployment
+        )
+        .fetch_all(&self.conn)
+        .await
+        .unwrap()
     }
 
     // TODO: implement this using SQL
@@ -520,7 +1143,7 @@ impl Db {
         let id = NanoId::random();
         let url_id = create_deployment_url_id();
         sqlx::query!(
-            "insert into deployments (id, slug, timestamp, created, sha, branch, default_branch, project) values (?, ?, ?, ?, ?, ?, ?, ?)",
+            "insert into deployments (id, slug, timestamp, created, sha, branch, default_branch, project, no_cache) values (?, ?, ?, ?, ?, ?, ?, ?, ?)",
             id,
             url_id,
             deployment.timestamp,
@@ -528,7 +1151,8 @@ impl Db {
             deployment.sha,
             deployment.branch,
             deployment.default_branch,
-            deployment.project
+            deployment.project,
+            deployment.no_cache
         )
         .execute(&self.conn)
         .await
@@ -536,15 +1160,22 @@ impl Db {
 
         for var in deployment.env {
             sqlx::query!(
-                "insert into deployment_env (name, value, deployment) values (?, ?, ?)",
+                // values come from the project env, so they are already encrypted and bound to
+                // the project of the deployment
+                "insert into deployment_env (name, value, scope, deployment, encrypted) values (?, ?, ?, ?, 1)",
                 var.name,
                 var.value,
+                var.scope,
                 id,
             )
             .execute(&self.conn)
             .await
             .unwrap();
         }
+
+        // new deployments start in the build queue
+        self.insert_deployment_event(&id, DeploymentEventKind::Queued, None)
+            .await;
     }
 
     #[tracing::instrument]
@@ -590,6 +1221,38 @@ impl Db {
         .unwrap();
     }
 
+    #[tracing::instrument]
+    pub(crate) async fn update_deployment_config(&self, id: &NanoId, config: &str) {
+        sqlx::query!("update deployments set config = ? where id = ?", config, id)
+            .execute(&self.conn)
+            .await
+            .unwrap();
+    }
+
+    /// average duration in milliseconds of the latest successful builds
+    #[tracing::instrument]
+    pub(crate) async fn get_average_build_duration(&self) -> Option<i64> {
+        sqlx::query_scalar!(
+            r#"select avg(build_finished - build_started) as "average: f64" from (select build_started, build_finished from deployments where result = 'built' and build_started is not null and build_finished is not null order by build_finished desc limit 20)"#
+        )
+        .fetch_one(&self.conn)
+        .await
+        .unwrap()
+        .map(|average| average as i64)
+    }
+
+    #[tracing::instrument]
+    pub(crate) async fn update_deployment_image(&self, id: &NanoId, image_id: Option<&str>) {
+        sqlx::query!(
+            "update deployments set image_id = ? where id = ?",
+            image_id,
+            id
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
     #[tracing::instrument]
     pub(crate) async fn get_deployment_build_logs(&self, deployment: &NanoId) -> Vec<BuildLog> {
         sqlx::query_as!(
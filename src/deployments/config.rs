@@ -0,0 +1,192 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{anyhow, ensure};
+use nixpacks::nixpacks::{
+    nix::pkg::Pkg,
+    plan::{
+        phase::{Phase, StartPhase},
+        BuildPlan,
+    },
+};
+use serde::Deserialize;
+
+use crate::{
+    container::ProxySettings,
+    routing::{HeaderRule, Redirect, Rewrite, RoutingRules},
+};
+
+use super::schema;
+
+const CONFIG_FILE: &str = "prezel.json";
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Visibility {
+    Standard,
+    Public,
+    Private,
+}
+
+/// Content of prezel.json. Any change here needs to be reflected in docs/public/prezel.schema.json,
+/// which is what the content is validated against
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DeploymentConfig {
+    #[serde(rename = "$schema")]
+    _schema: Option<String>,
+    visibility: Option<Visibility>,
+    #[serde(default)]
+    redirects: Vec<Redirect>,
+    #[serde(default)]
+    rewrites: Vec<Rewrite>,
+    #[serde(default)]
+    headers: Vec<HeaderRule>,
+    #[serde(default)]
+    build: BuildConfig,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BuildConfig {
+    /// directory sent to docker, relative to the app root
+    pub(crate) context: Option<String>,
+    /// relative to the build context
+    pub(crate) dockerfile: Option<String>,
+    /// stage of a multi-stage Dockerfile used as the final image
+    pub(crate) target: Option<String>,
+    /// take precedence over the env vars with the same name
+    #[serde(default)]
+    pub(crate) args: HashMap<String, String>,
+    #[serde(default)]
+    pub(crate) nixpacks: NixpacksConfig,
+    /// the database url and token are only passed to the build if this is set, as they might
+    /// end up stored in the image
+    #[serde(default, rename = "databaseEnv")]
+    pub(crate) database_env: bool,
+}
+
+/// Only used when the build context has no Dockerfile
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub(crate) struct NixpacksConfig {
+    providers: Option<Vec<String>>,
+    install_command: Option<String>,
+    build_command: Option<String>,
+    start_command: Option<String>,
+    #[serde(default)]
+    packages: Vec<String>,
+}
+
+impl NixpacksConfig {
+    /// mirrors what the nixpacks CLI does with --install-cmd, --build-cmd, etc.
+    pub(crate) fn get_plan(&self) -> BuildPlan {
+        let mut plan = BuildPlan {
+            providers: self.providers.clone(),
+            ..Default::default()
+        };
+        if !self.packages.is_empty() {
+            // "..." keeps the packages detected by the providers
+            let pkgs = self.packages.iter().map(String::as_str).chain(["..."]);
+            plan.add_phase(Phase::setup(Some(pkgs.map(Pkg::new).collect())));
+        }
+        if let Some(install) = &self.install_command {
+            let mut phase = Phase::install(None);
+            phase.cmds = Some(vec![install.clone()]);
+            plan.add_phase(phase);
+        }
+        if let Some(build) = &self.build_command {
+            let mut phase = Phase::build(None);
+            phase.cmds = Some(vec![build.clone()]);
+            plan.add_phase(phase);
+        }
+        if let Some(start) = &self.start_command {
+            plan.set_start_phase(StartPhase::new(start));
+        }
+        plan
+    }
+}
+
+impl DeploymentConfig {
+    pub(crate) fn parse(content: &str) -> anyhow::Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(content)
+            .map_err(|error| anyhow!("Invalid {CONFIG_FILE}: {error}"))?;
+        schema::validate(&value).map_err(|error| anyhow!("Invalid {CONFIG_FILE}: {error}"))?;
+        let config: Self = serde_json::from_value(value)
+            .map_err(|error| anyhow!("Invalid {CONFIG_FILE}: {error}"))?;
+        for (key, path) in [
+            ("build.context", &config.build.context),
+            ("build.dockerfile", &config.build.dockerfile),
+        ] {
+            if let Some(path) = path {
+                ensure!(
+                    !path.is_empty() && Path::new(path).is_relative(),
+                    "Invalid {CONFIG_FILE}: {key} has to be a relative path"
+                );
+            }
+        }
+        Ok(config)
+    }
+
+    pub(crate) fn get_build_config(&self) -> &BuildConfig {
+        &self.build
+    }
+
+    /// returns the raw content of prezel.json, or an empty object if the file is missing
+    pub(crate) async fn read_from_context(root: &Path) -> anyhow::Result<String> {
+        let path = root.join(CONFIG_FILE);
+        if tokio::fs::try_exists(&path).await? {
+            Ok(tokio::fs::read_to_string(&path).await?)
+        } else {
+            Ok("{}".to_owned())
+        }
+    }
+
+    pub(crate) fn get_proxy_settings(&self, default_branch: bool) -> ProxySettings {
+        ProxySettings {
+            public: self.is_public(default_branch),
+            routes: self.get_routing_rules().into(),
+        }
+    }
+
+    fn is_public(&self, default_branch: bool) -> bool {
+        match self.visibility.unwrap_or(Visibility::Standard) {
+            Visibility::Standard => default_branch,
+            Visibility::Public => true,
+            Visibility::Private => false,
+        }
+    }
+
+    fn get_routing_rules(&self) -> RoutingRules {
+        RoutingRules {
+            redirects: self.redirects.clone(),
+            rewrites: self.rewrites.clone(),
+            headers: self.headers.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::DeploymentConfig;
+
+    #[test]
+    fn test_strict_parsing() {
+        let config = DeploymentConfig::parse(
+            r#"{"$schema": "./prezel.schema.json", "visibility": "public"}"#,
+        )
+        .unwrap();
+        assert!(config.is_public(false));
+
+        let unknown_key = DeploymentConfig::parse(r#"{"visibilty": "public"}"#);
+        assert!(unknown_key
+            .unwrap_err()
+            .to_string()
+            .contains("/visibilty is not allowed"));
+
+        let wrong_type = DeploymentConfig::parse(r#"{"redirects": {}}"#);
+        assert!(wrong_type.is_err());
+
+        let absolute_context = DeploymentConfig::parse(r#"{"build": {"context": "/etc"}}"#);
+        assert!(absolute_context.is_err());
+    }
+}
@@ -1,15 +1,17 @@
 use std::future::Future;
-use std::path::{Component, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
 
 use futures::{stream, Stream, StreamExt};
-use serde::Deserialize;
+use tracing::error;
 
 use crate::container::commit::CommitContainer;
 use crate::container::ContainerStatus;
-use crate::db::{nano_id::NanoId, BuildResult, Deployment as DbDeployment};
+use crate::db::{nano_id::NanoId, BuildResult, Deployment as DbDeployment, EnvScope};
+use crate::docker::get_image_id;
+use crate::env::EnvVars;
 use crate::hooks::StatusHooks;
+use crate::log_drain::LogDrains;
 use crate::sqlite_db::ProdSqliteDb;
 use crate::Conf;
 use crate::{
@@ -18,7 +20,7 @@ use crate::{
     github::Github,
 };
 
-use super::worker::WorkerHandle;
+use super::{config::DeploymentConfig, worker::WorkerHandle};
 
 #[derive(Debug, Clone)]
 pub(crate) struct Deployment {
@@ -34,40 +36,6 @@ pub(crate) struct Deployment {
     pub(crate) app_container: Arc<Container>, // FIXME: try to remove Arc, only needed to make access to socket/public generic
 }
 
-#[derive(Deserialize, Clone, Copy)]
-#[serde(rename_all = "lowercase")]
-enum Visibility {
-    Standard,
-    Public,
-    Private,
-}
-
-#[derive(Deserialize, Default)]
-struct DeploymentConfig {
-    visibility: Option<Visibility>,
-}
-
-impl DeploymentConfig {
-    // FIXME: if there's just a network problem, I might interpret as there is no file
-    // and make the prod deployment public when it was indeed set to private in the repo
-    async fn fetch_from_repo(github: &Github, repo_id: i64, sha: &str, root: &str) -> Option<Self> {
-        let conf_path = PathBuf::from(root).join("prezel.json");
-        let valid_components = conf_path
-            .components()
-            .filter(|comp| !matches!(comp, Component::CurDir));
-        let valid_path: PathBuf = valid_components.collect();
-        let content = github
-            .download_file(repo_id, &sha, valid_path.to_str()?)
-            .await
-            .ok()?;
-        serde_json::from_str(&content).ok()
-    }
-
-    fn get_visibility(&self) -> Visibility {
-        self.visibility.clone().unwrap_or(Visibility::Standard)
-    }
-}
-
 impl Deployment {
     pub(crate) fn iter_arc_containers(&self) -> impl Stream<Item = Arc<Container>> + Send + '_ {
         let containers: [Pin<Box<dyn Future<Output = Option<Arc<Container>>> + Send>>; 2] = [
@@ -90,6 +58,7 @@ impl Deployment {
         github: Github,
         db: Db,
         project_db: &ProdSqliteDb,
+        log_drains: LogDrains,
     ) -> Self {
         let Conf { hostname, .. } = Conf::read_async().await; // TODO: take this from args?
         let db_url = deployment.get_libsql_url(&hostname);
@@ -106,24 +75,37 @@ impl Deployment {
             url_id,
             timestamp,
             created,
+            config,
+            image_id,
+            no_cache,
             ..
         } = deployment;
 
-        let conf = DeploymentConfig::fetch_from_repo(&github, project.repo_id, &sha, &project.root)
-            .await
-            .unwrap_or_default();
-        let is_public = match conf.get_visibility() {
-            Visibility::Standard => default_branch,
-            Visibility::Public => true,
-            Visibility::Private => false,
-        };
+        // prezel.json is only known once the deployment has been built at least once
+        let config = config.and_then(|config| {
+            DeploymentConfig::parse(&config)
+                .inspect_err(|error| error!("ignoring stored config for deployment {id}: {error}"))
+                .ok()
+        });
 
-        let env = env.into();
-        let hooks = StatusHooks::new(id.clone(), db, github.clone());
+        let (build_args, build_secrets) = EnvVars::build_scoped(&env, &project.id, db.env_cipher());
+        let runtime_env = EnvVars::scoped(&env, EnvScope::Runtime, &project.id, db.env_cipher());
+        let hooks = StatusHooks::new(id.clone(), db, github.clone(), log_drains);
 
         let (inistial_status, build_result) = match deployment.result {
             Some(BuildResult::Failed) => (ContainerStatus::Failed, Some(BuildResult::Failed)),
-            Some(BuildResult::Built) => (ContainerStatus::Built, Some(BuildResult::Built)),
+            Some(BuildResult::Cancelled) => {
+                (ContainerStatus::Cancelled, Some(BuildResult::Cancelled))
+            }
+            Some(BuildResult::Skipped) => (ContainerStatus::Skipped, Some(BuildResult::Skipped)),
+            Some(BuildResult::Built) => {
+                // the image is only reused if it was not removed while prezel was down
+                let image = match image_id {
+                    Some(image) if get_image_id(&image).await.is_some() => Some(image),
+                    _ => None,
+                };
+                (ContainerStatus::Built { image }, Some(BuildResult::Built))
+            }
             _ => (
                 ContainerStatus::Queued {
                     trigger_access: None,
@@ -140,10 +122,14 @@ impl Deployment {
             project.repo_id,
             sha.clone(),
             id.clone(),
-            env,
+            project.id.clone(),
+            no_cache,
+            build_args,
+            build_secrets,
+            runtime_env,
             project.root.clone(),
             is_branch_deployment,
-            is_public,
+            config,
             project_db,
             &db_url,
             inistial_status,
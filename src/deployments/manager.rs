@@ -1,12 +1,17 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashMap, ops::Deref, sync::Arc, time::Duration};
 
+use futures::StreamExt;
 use tokio::sync::RwLock;
 
 use crate::{
-    container::Container,
-    db::{nano_id::NanoId, Db},
+    analytics::Analytics,
+    container::{Container, ContainerStatus},
+    db::{nano_id::NanoId, Db, Project},
     github::Github,
+    ip_filter::Environment,
     label::Label,
+    log_drain::LogDrains,
+    rate_limit::RateLimiter,
     sqlite_db::SqliteDbSetup,
     tls::CertificateStore,
 };
@@ -18,6 +23,32 @@ use super::{
     workers::{build::BuildWorker, docker::DockerWorker, files::FilesWorker, github::GithubWorker},
 };
 
+/// Container serving some hostname. The project is only set for app containers
+pub(crate) struct HostedContainer {
+    pub(crate) container: Arc<Container>,
+    pub(crate) project: Option<Arc<Project>>,
+    pub(crate) environment: Environment,
+}
+
+impl From<Arc<Container>> for HostedContainer {
+    fn from(container: Arc<Container>) -> Self {
+        Self {
+            container,
+            project: None,
+            environment: Environment::Production,
+        }
+    }
+}
+
+/// Position of a deployment in the build queue
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct QueuedBuild {
+    /// starting at 1 for the next deployment to be built
+    pub(crate) position: usize,
+    /// in seconds, missing if no build has finished yet
+    pub(crate) estimated_wait: Option<i64>,
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct Manager {
     pub(crate) box_domain: String,
@@ -28,6 +59,10 @@ pub(crate) struct Manager {
     files_worker: Arc<WorkerHandle>,
     db: Db,
     github: Github,
+    pub(crate) rate_limiter: RateLimiter,
+    pub(crate) analytics: Analytics,
+    pub(crate) log_drains: LogDrains,
+    build_concurrency: usize,
 }
 
 // workers:
@@ -42,8 +77,11 @@ impl Manager {
         github: Github,
         db: Db,
         certificates: CertificateStore,
+        build_concurrency: usize,
     ) -> Self {
-        let deployments: Arc<_> = InstrumentedRwLock::new(DeploymentMap::new(certificates)).into();
+        let log_drains = LogDrains::start(db.clone(), box_domain.clone());
+        let map = DeploymentMap::new(certificates, log_drains.clone());
+        let deployments: Arc<_> = InstrumentedRwLock::new(map).into();
 
         let github_clone = github.clone();
         let db_clone = db.clone();
@@ -53,6 +91,7 @@ impl Manager {
             db: db_clone,
             github: github_clone,
             build_queue,
+            concurrency: build_concurrency,
         })
         .into();
 
@@ -83,7 +122,12 @@ impl Manager {
             files_worker,
             db,
             github,
+            rate_limiter: Default::default(),
+            analytics: Default::default(),
+            log_drains,
+            build_concurrency,
         };
+        manager.analytics.start_flushing(manager.db.clone());
 
         // TODO: reset the timer every time full_sync_with_github is executed triggered by something else
         let cloned_manager = manager.clone();
@@ -99,13 +143,19 @@ impl Manager {
     }
 
     #[tracing::instrument]
-    pub(crate) async fn get_container_by_hostname(&self, hostname: &str) -> Option<Arc<Container>> {
-        let container = self
-            .deployments
-            .read()
-            .await
-            .get_custom_domain(hostname)
-            .map(|deployment| deployment.app_container.clone());
+    pub(crate) async fn get_container_by_hostname(
+        &self,
+        hostname: &str,
+    ) -> Option<HostedContainer> {
+        let container = {
+            let map = self.deployments.read().await;
+            map.get_custom_domain(hostname)
+                .map(|deployment| HostedContainer {
+                    container: deployment.app_container.clone(),
+                    project: map.get_project(&deployment.project),
+                    environment: Environment::Production,
+                })
+        };
         if let Some(container) = container {
             Some(container)
         } else {
@@ -115,19 +165,32 @@ impl Manager {
     }
 
     #[tracing::instrument]
-    async fn get_container_by_label(&self, label: Label) -> Option<Arc<Container>> {
+    async fn get_container_by_label(&self, label: Label) -> Option<HostedContainer> {
         let map = self.deployments.read().await;
         match &label {
             Label::Prod { project } => {
                 let deployment = map.get_prod(project)?;
-                Some(deployment.app_container.clone())
+                Some(HostedContainer {
+                    container: deployment.app_container.clone(),
+                    project: map.get_project(&deployment.project),
+                    environment: Environment::Production,
+                })
             }
             Label::Deployment {
                 project,
                 deployment,
             } => {
                 let deployment = map.get_deployment(project, deployment)?;
-                Some(deployment.app_container.clone())
+                let environment = if deployment.default_branch {
+                    Environment::Production
+                } else {
+                    Environment::Preview
+                };
+                Some(HostedContainer {
+                    container: deployment.app_container.clone(),
+                    project: map.get_project(&deployment.project),
+                    environment,
+                })
             }
             Label::BranchDb {
                 project,
@@ -139,12 +202,67 @@ impl Manager {
                     .read()
                     .await
                     .get_db_setup()
-                    .map(|setup| setup.container.clone())
+                    .map(|setup| setup.container.clone().into())
             }
             Label::ProdDb { project } => map
                 .get_prod_db_by_name(project)
-                .map(|setup| setup.container.clone()),
+                .map(|setup| setup.container.clone().into()),
+        }
+    }
+
+    /// number of containers for each status, including the db ones
+    #[tracing::instrument]
+    pub(crate) async fn get_container_counts(&self) -> HashMap<&'static str, usize> {
+        let map = self.deployments.read().await;
+        let mut containers = map.iter_containers();
+        let mut counts = HashMap::new();
+        while let Some(container) = containers.next().await {
+            let status = container.status.read().await.name();
+            *counts.entry(status).or_default() += 1;
         }
+        counts
+    }
+
+    /// position in the build queue of every queued deployment
+    #[tracing::instrument]
+    pub(crate) async fn get_queued_builds(&self) -> HashMap<NanoId, QueuedBuild> {
+        let (queue, running) = {
+            let map = self.deployments.read().await;
+            let queue: Vec<NanoId> = map
+                .get_build_queue()
+                .await
+                .into_iter()
+                .map(|deployment| deployment.id.clone())
+                .collect();
+            let mut running = 0;
+            for deployment in map.deployments.values() {
+                let status = deployment.app_container.status.read().await;
+                if let ContainerStatus::Building { .. } = status.deref() {
+                    running += 1;
+                }
+            }
+            (queue, running)
+        };
+        let average = self.db.get_average_build_duration().await;
+        queue
+            .into_iter()
+            .enumerate()
+            .map(|(index, id)| {
+                // builds run in rounds of build_concurrency, each one taking the average build duration
+                let rounds = (index + running) / self.build_concurrency.max(1);
+                let queued = QueuedBuild {
+                    position: index + 1,
+                    estimated_wait: average.map(|average| rounds as i64 * average / 1000),
+                };
+                (id, queued)
+            })
+            .collect()
+    }
+
+    #[tracing::instrument]
+    pub(crate) async fn get_certificate_expiries(&self) -> Vec<(String, i64)> {
+        let certificates = self.deployments.read().await.certificates.clone();
+        certificates.get_expiries()
     }
 
     #[tracing::instrument]
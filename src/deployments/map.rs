@@ -5,11 +5,14 @@ use std::{
 };
 
 use futures::{stream, Stream, StreamExt};
+use tracing::error;
 
 use crate::{
     container::{Container, ContainerStatus},
-    db::{nano_id::NanoId, BuildResult, Db},
+    db::{nano_id::NanoId, BuildResult, Db, DeploymentEventKind, Project},
     github::Github,
+    log_drain::LogDrains,
+    notifications::{notify, NotificationEvent},
     sqlite_db::{ProdSqliteDb, SqliteDbSetup},
     tls::CertificateStore,
 };
@@ -24,19 +27,23 @@ pub(crate) struct DeploymentMap {
     /// values here used to be options, but removing them from the map should be enough
     pub(crate) prod: HashMap<NanoId, String>, // project id -> deployment slug
     pub(crate) names: HashMap<String, NanoId>, // project name -> project id
+    pub(crate) projects: HashMap<NanoId, Arc<Project>>, // project id -> project
     pub(crate) certificates: CertificateStore,
     pub(crate) custom_domains: HashMap<String, NanoId>, // domain -> project id
+    log_drains: LogDrains,
 }
 
 impl DeploymentMap {
-    pub(crate) fn new(store: CertificateStore) -> Self {
+    pub(crate) fn new(store: CertificateStore, log_drains: LogDrains) -> Self {
         Self {
             dbs: Default::default(),
             deployments: Default::default(),
             prod: Default::default(),
             names: Default::default(),
+            projects: Default::default(),
             custom_domains: Default::default(),
             certificates: store,
+            log_drains,
         }
     }
 
@@ -48,6 +55,30 @@ impl DeploymentMap {
         stream::iter(prod_dbs).chain(deployments)
     }
 
+    /// Queued deployments in the order they should be built: production deployments first, then
+    /// the ones someone is waiting on, by access time, and then the rest by commit time
+    #[tracing::instrument]
+    pub(crate) async fn get_build_queue(&self) -> Vec<&Deployment> {
+        let mut queue = vec![];
+        for deployment in self.deployments.values() {
+            let status = deployment.app_container.status.read().await.clone();
+            if let ContainerStatus::Queued { trigger_access } = status {
+                let priority = (
+                    !deployment.default_branch,
+                    trigger_access.is_none(),
+                    trigger_access,
+                    deployment.timestamp,
+                );
+                queue.push((priority, deployment));
+            }
+        }
+        queue.sort_by_key(|(priority, _)| *priority);
+        queue
+            .into_iter()
+            .map(|(_, deployment)| deployment)
+            .collect()
+    }
+
     #[tracing::instrument]
     pub(crate) fn get_deployment(&self, project: &str, deployment: &str) -> Option<&Deployment> {
         let project_id = self.names.get(project)?;
@@ -74,6 +105,11 @@ impl DeploymentMap {
         self.get_prod_from_id(project_id)
     }
 
+    #[tracing::instrument]
+    pub(crate) fn get_project(&self, id: &NanoId) -> Option<Arc<Project>> {
+        self.projects.get(id).cloned()
+    }
+
     #[tracing::instrument]
     pub(crate) fn get_prod_db(&self, id: &NanoId) -> Option<SqliteDbSetup> {
         self.dbs.get(id).map(|db| db.setup.clone())
@@ -116,6 +152,7 @@ impl DeploymentMap {
             .iter()
             .map(|(id, project)| (project.name.clone(), id.clone()))
             .collect();
+        self.projects = projects.clone();
 
         // sync map.custom_domains
         self.custom_domains = projects
@@ -163,6 +200,7 @@ impl DeploymentMap {
                         github.clone(),
                         db.clone(),
                         prod_db,
+                        self.log_drains.clone(),
                     )
                     .await;
                     self.deployments.insert((project, url_id), deployment);
@@ -177,6 +215,7 @@ impl DeploymentMap {
                 self.deployments.remove(&id);
             }
         }
+        self.skip_superseded_builds().await;
 
         // sync map.prod
         self.prod = stream::iter(projects)
@@ -224,6 +263,7 @@ impl DeploymentMap {
             })
             .collect()
             .await;
+        self.record_prod_changes(db).await;
         // TODO: lots of clones going on above, the code below seems so close to work...
         // self.prod = stream::iter(projects)
         //     .filter_map(|(id, _)| async {
@@ -258,7 +298,13 @@ impl DeploymentMap {
                 // the logic to put containers into the queue is a bit duplicated.
                 // Maybe everything related to putting containers into the Queue should be here,
                 // but that means I need an additional status
-                ContainerStatus::Built { .. } => {
+                ContainerStatus::Built { image: Some(_) } => {
+                    if let Err(error) = deployment.app_container.restore().await {
+                        error!("failed to restore deployment {}: {error}", deployment.id);
+                        deployment.app_container.enqueue().await;
+                    }
+                }
+                ContainerStatus::Built { image: None } => {
                     deployment.app_container.enqueue().await;
                 }
                 _ => {}
@@ -271,6 +317,65 @@ impl DeploymentMap {
         }
     }
 
+    /// Deployments that were never built are skipped once a newer deployment is created for the
+    /// same branch. Running builds are only stopped if the project opted into it
+    async fn skip_superseded_builds(&self) {
+        let mut latest: HashMap<(&NanoId, &str), i64> = HashMap::new();
+        for deployment in self.deployments.values() {
+            let created = latest
+                .entry((&deployment.project, &deployment.branch))
+                .or_default();
+            *created = (*created).max(deployment.created);
+        }
+        for deployment in self.deployments.values() {
+            let latest = latest[&(&deployment.project, deployment.branch.as_str())];
+            let never_built = deployment.app_container.result.read().await.is_none();
+            if deployment.created < latest && never_built {
+                let include_running = self
+                    .projects
+                    .get(&deployment.project)
+                    .is_some_and(|project| project.cancel_superseded_builds);
+                deployment.app_container.skip_build(include_running).await;
+            }
+        }
+    }
+
+    /// compares prod with the deployment recorded in the db, so the first production deployment
+    /// of a project is notified too, and restarts don't notify again
+    async fn record_prod_changes(&self, db: &Db) {
+        for (project, slug) in &self.prod {
+            let Some(deployment) = self.deployments.get(&(project.clone(), slug.clone())) else {
+                continue;
+            };
+            // until a deployment is built, the latest one is only prod as a fallback
+            if *deployment.app_container.result.read().await != Some(BuildResult::Built) {
+                continue;
+            }
+            let promoted = self
+                .projects
+                .get(project)
+                .and_then(|project| project.promoted_deployment.as_ref());
+            if promoted == Some(&deployment.id) {
+                continue;
+            }
+            let previous =
+                promoted.and_then(|id| self.deployments.values().find(|other| &other.id == id));
+            let (kind, event) = match previous {
+                Some(previous) if previous.created > deployment.created => {
+                    (DeploymentEventKind::Rollback, NotificationEvent::Rollback)
+                }
+                _ => (
+                    DeploymentEventKind::PromotedToProd,
+                    NotificationEvent::PromotedToProd,
+                ),
+            };
+            db.update_project_promoted_deployment(project, &deployment.id)
+                .await;
+            db.insert_deployment_event(&deployment.id, kind, None).await;
+            notify(db, event, &deployment.id, None);
+        }
+    }
+
     #[tracing::instrument]
     fn iter_prod_deployments(&self) -> impl Iterator<Item = &Deployment> {
         self.names
@@ -1,5 +1,7 @@
+pub(crate) mod config;
 pub(crate) mod deployment;
 pub(crate) mod manager;
 mod map;
+mod schema;
 pub(crate) mod worker;
 mod workers;
@@ -0,0 +1,149 @@
+use anyhow::{anyhow, bail, ensure};
+use regex::Regex;
+use serde_json::{Map, Value};
+
+/// The published schema is the source of truth for what prezel.json accepts
+const SCHEMA: &str = include_str!("../../docs/public/prezel.schema.json");
+
+/// Validates against the subset of JSON schema used by prezel.schema.json. Returns the path of
+/// the first invalid value along with the reason
+pub(crate) fn validate(config: &Value) -> anyhow::Result<()> {
+    let schema: Value = serde_json::from_str(SCHEMA)?;
+    Validator { root: &schema }.validate(&schema, config, "")
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+}
+
+impl<'a> Validator<'a> {
+    fn validate(&self, schema: &'a Value, value: &Value, path: &str) -> anyhow::Result<()> {
+        let schema = self.resolve(schema)?;
+        let location = if path.is_empty() { "/" } else { path };
+
+        if let Some(expected) = schema.get("type").and_then(Value::as_str) {
+            ensure!(
+                has_type(value, expected),
+                "{location} should be of type {expected}"
+            );
+        }
+        if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+            ensure!(
+                options.contains(value),
+                "{location} should be one of {}",
+                Value::Array(options.clone())
+            );
+        }
+        if let (Some(pattern), Some(text)) = (
+            schema.get("pattern").and_then(Value::as_str),
+            value.as_str(),
+        ) {
+            ensure!(
+                Regex::new(pattern)?.is_match(text),
+                "{location} should match {pattern}"
+            );
+        }
+        if let Some(object) = value.as_object() {
+            self.validate_object(schema, object, path)?;
+        }
+        if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+            for (index, item) in array.iter().enumerate() {
+                self.validate(items, item, &format!("{path}/{index}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_object(
+        &self,
+        schema: &'a Value,
+        object: &Map<String, Value>,
+        path: &str,
+    ) -> anyhow::Result<()> {
+        let empty = Map::new();
+        let properties = schema
+            .get("properties")
+            .and_then(Value::as_object)
+            .unwrap_or(&empty);
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for key in required.iter().filter_map(Value::as_str) {
+                ensure!(object.contains_key(key), "{path}/{key} is required");
+            }
+        }
+        for (key, value) in object {
+            let property_path = format!("{path}/{key}");
+            match (properties.get(key), schema.get("additionalProperties")) {
+                (Some(property), _) => self.validate(property, value, &property_path)?,
+                (None, Some(Value::Bool(false))) => bail!("{property_path} is not allowed"),
+                (None, Some(additional @ Value::Object(_))) => {
+                    self.validate(additional, value, &property_path)?
+                }
+                (None, _) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// only local references like #/definitions/source are supported
+    fn resolve(&self, schema: &'a Value) -> anyhow::Result<&'a Value> {
+        match schema.get("$ref").and_then(Value::as_str) {
+            Some(reference) => reference
+                .strip_prefix('#')
+                .and_then(|pointer| self.root.pointer(pointer))
+                .ok_or(anyhow!("Unsupported schema reference {reference}")),
+            None => Ok(schema),
+        }
+    }
+}
+
+fn has_type(value: &Value, expected: &str) -> bool {
+    match expected {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod schema_tests {
+    use serde_json::json;
+
+    use super::validate;
+
+    #[test]
+    fn test_schema_validation() {
+        let valid = json!({
+            "$schema": "./prezel.schema.json",
+            "visibility": "public",
+            "redirects": [{ "source": "/old/:path*", "destination": "/new/:path*", "statusCode": 301 }],
+            "rewrites": [{ "source": "/user/:id", "destination": "/api/users?id=:id" }],
+            "headers": [{ "source": "/:path*", "headers": [{ "key": "X-Frame-Options", "value": "DENY" }] }],
+            "build": {
+                "context": "..",
+                "dockerfile": "Dockerfile.prod",
+                "target": "runner",
+                "args": { "NODE_VERSION": "20" },
+                "databaseEnv": true,
+                "nixpacks": { "providers": ["node"], "startCommand": "npm start", "packages": ["ffmpeg"] }
+            }
+        });
+        validate(&valid).unwrap();
+
+        let unknown_key = validate(&json!({ "build": { "dockerFile": "Dockerfile" } }));
+        assert_eq!(
+            unknown_key.unwrap_err().to_string(),
+            "/build/dockerFile is not allowed"
+        );
+        assert!(validate(&json!({ "visibility": "hidden" })).is_err());
+        assert!(
+            validate(&json!({ "rewrites": [{ "source": "user", "destination": "/" }] })).is_err()
+        );
+        assert!(validate(&json!({ "redirects": [{ "source": "/" }] })).is_err());
+        assert!(validate(&json!({ "build": { "args": { "A": 1 } } })).is_err());
+    }
+}
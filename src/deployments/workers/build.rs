@@ -1,11 +1,11 @@
-use std::{future::Future, sync::Arc};
+use std::{future::Future, sync::Arc, time::Duration};
 
-use futures::StreamExt;
-use rand::seq::SliceRandom;
+use futures::future;
+use tokio::{task::JoinHandle, time::sleep};
 use tracing::error;
 
 use crate::{
-    container::{Container, ContainerStatus},
+    container::Container,
     db::Db,
     deployments::{
         manager::InstrumentedRwLock,
@@ -15,6 +15,11 @@ use crate::{
     github::Github,
 };
 
+/// in seconds, used for projects without a build timeout
+pub(crate) const DEFAULT_BUILD_TIMEOUT: i64 = 30 * 60;
+/// how often the queue is checked for new builds while others are running
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Clone, Debug)]
 pub(crate) struct BuildWorker {
     // TODO: define a new function instead of having these public, same for other workers
@@ -22,27 +27,53 @@ pub(crate) struct BuildWorker {
     pub(crate) db: Db,
     pub(crate) github: Github,
     pub(crate) build_queue: WorkerHandle,
+    pub(crate) concurrency: usize,
 }
 
 impl Worker for BuildWorker {
     #[tracing::instrument]
     fn work(&self) -> impl Future<Output = ()> + Send {
         async {
+            // every build runs in its own task so they keep going while the map is being updated
+            let mut builds: Vec<(Arc<Container>, JoinHandle<()>)> = vec![];
             loop {
-                if let Some(container) = self.get_container_to_build().await {
-                    let result = container.setup_as_standby().await;
+                while builds.len() < self.concurrency.max(1) {
+                    let Some(container) = self.get_container_to_build(&builds).await else {
+                        break;
+                    };
+                    let timeout = self.get_build_timeout(&container).await;
+                    let build = tokio::spawn({
+                        let container = container.clone();
+                        async move {
+                            let result = container.setup_as_standby(timeout).await;
+                            if let Err(error) = result {
+                                error!("got error when setting up a container: {error}")
+                            }
+                        }
+                    });
+                    builds.push((container, build));
+                }
+                if builds.is_empty() {
+                    break;
+                }
+
+                // containers queued while building are picked up without waiting for a free slot
+                let handles = builds.iter_mut().map(|(_, build)| build);
+                let finished = tokio::select! {
+                    (result, index, _) = future::select_all(handles) => Some((index, result)),
+                    _ = sleep(QUEUE_POLL_INTERVAL) => None,
+                };
+                if let Some((index, result)) = finished {
+                    builds.swap_remove(index);
                     if let Err(error) = result {
-                        error!("got error when setting up a container: {error}")
+                        error!("build task failed: {error}");
                     }
-
                     // we call this because the container we just built might be promoted to be the prod one
                     self.map
                         .write()
                         .await
                         .read_db_and_build_updates(&self.build_queue, &self.github, &self.db)
                         .await;
-                } else {
-                    break;
                 }
             }
         }
@@ -51,35 +82,33 @@ impl Worker for BuildWorker {
 
 impl BuildWorker {
     #[tracing::instrument]
-    async fn get_container_to_build(&self) -> Option<Arc<Container>> {
+    async fn get_build_timeout(&self, container: &Container) -> Duration {
+        let project = match &container.logging_deployment_id {
+            Some(id) => self.db.get_deployment_with_project(id).await,
+            None => None,
+        };
+        let seconds = project
+            .and_then(|deployment| deployment.project.build_timeout)
+            .unwrap_or(DEFAULT_BUILD_TIMEOUT);
+        Duration::from_secs(seconds as u64)
+    }
+
+    /// highest priority queued container that is not already being built
+    #[tracing::instrument]
+    async fn get_container_to_build(
+        &self,
+        building: &[(Arc<Container>, JoinHandle<()>)],
+    ) -> Option<Arc<Container>> {
         // this block helds this read guard
         let map = self.map.read().await;
-        let queued_containers = map
-            .iter_containers()
-            .filter_map(|container| async {
-                let status = container.status.read().await.clone();
-                if let ContainerStatus::Queued { trigger_access } = status {
-                    Some((container, trigger_access))
-                } else {
-                    None
-                }
+        map.get_build_queue()
+            .await
+            .into_iter()
+            .map(|deployment| deployment.app_container.clone())
+            .find(|container| {
+                !building
+                    .iter()
+                    .any(|(other, _)| Arc::ptr_eq(other, container))
             })
-            .collect::<Vec<_>>()
-            .await;
-
-        let first_container_accessed = queued_containers
-            .iter()
-            .filter_map(|(container, trigger_access)| Some((container, trigger_access.clone()?)))
-            .min_by_key(|(_, trigger_access)| trigger_access.clone())
-            .map(|(container, _)| container);
-
-        if first_container_accessed.is_some() {
-            first_container_accessed.cloned()
-        } else {
-            // if no container is accessed, just return a random one
-            queued_containers
-                .choose(&mut rand::thread_rng())
-                .map(|(container, _)| container.clone())
-        }
     }
 }
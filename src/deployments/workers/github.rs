@@ -1,6 +1,7 @@
 use crate::{
     db::{Db, InsertDeployment, Project},
     deployments::worker::Worker,
+    env::resolve_deployment_env,
     github::{Commit, Github},
 };
 
@@ -21,12 +22,13 @@ impl Worker for GithubWorker {
                 let commit = get_default_branch_and_latest_commit(&self.github, repo_id).await;
                 if let Ok((default_branch, commit)) = commit {
                     let deployment = InsertDeployment {
-                        env: env.to_owned(),
+                        env: resolve_deployment_env(&env, &default_branch, true),
                         sha: commit.sha,
                         timestamp: commit.timestamp,
                         branch: default_branch,
                         default_branch: 1, // TODO: abstract this as a bool
                         project: id.clone(),
+                        no_cache: false,
                     };
                     add_deployment_to_db_if_missing(&self.db, deployment).await;
                 }
@@ -37,12 +39,13 @@ impl Worker for GithubWorker {
                     // FIXME: some duplicated code in here as in above
                     if let Ok(commit) = self.github.get_latest_commit(repo_id, &branch).await {
                         let deployment = InsertDeployment {
-                            env: env.to_owned(),
+                            env: resolve_deployment_env(&env, &branch, false),
                             sha: commit.sha,
                             timestamp: commit.timestamp,
                             branch,
                             default_branch: 0, // TODO: abstract this as a bool
                             project: id.clone(),
+                            no_cache: false,
                         };
                         add_deployment_to_db_if_missing(&self.db, deployment).await;
                     }
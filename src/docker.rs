@@ -4,25 +4,34 @@ use anyhow::anyhow;
 use bollard::{
     container::{
         Config, CreateContainerOptions, ListContainersOptions, LogOutput, LogsOptions,
-        NetworkingConfig, StartContainerOptions,
+        NetworkingConfig, StartContainerOptions, WaitContainerOptions,
     },
     errors::Error as DockerError,
-    image::{BuildImageOptions, CreateImageOptions},
-    secret::{BuildInfo, HostConfig},
+    grpc::{
+        build::{ImageBuildFrontendOptions, ImageBuildLoadInput, SecretSource},
+        driver::{moby::Moby, Build},
+    },
+    image::{BuildImageOptions, BuilderVersion, CreateImageOptions},
+    moby::buildkit::v1::StatusResponse,
+    secret::{BuildInfo, BuildInfoAux, HostConfig},
     Docker,
 };
 use chrono::{DateTime, Utc};
-use futures::StreamExt;
+use flate2::{write::GzEncoder, Compression};
+use futures::{stream, Stream, StreamExt};
 use hyper::body::Bytes;
 use nanoid::nanoid;
 use serde::Serialize;
 use std::{
+    collections::{HashMap, HashSet},
     error::Error,
+    fs,
     future::{self, Future},
     net::Ipv4Addr,
     path::{Path, PathBuf},
-    pin::Pin,
 };
+use tempfile::TempDir;
+use tokio::{runtime::Handle, sync::oneshot, task};
 use utoipa::ToSchema;
 
 use crate::{env::EnvVars, utils::LOWERCASE_PLUS_NUMBERS};
@@ -43,6 +52,7 @@ impl ImageName {
         format!("{CONTAINER_PREFIX}{}", self.0)
     }
 }
+
 impl From<String> for ImageName {
     fn from(value: String) -> Self {
         Self(value)
@@ -110,6 +120,54 @@ pub(crate) async fn get_container_execution_logs(id: &str) -> impl Iterator<Item
     })
 }
 
+/// exit code of the container once it stops, or None if docker could not be reached
+pub(crate) async fn wait_for_container_exit(id: &str) -> Option<i64> {
+    let docker = docker_client();
+    let mut responses = docker.wait_container(id, None::<WaitContainerOptions<String>>);
+    match responses.next().await? {
+        Ok(response) => Some(response.status_code),
+        Err(DockerError::DockerContainerWaitError { code, .. }) => Some(code),
+        Err(_) => None,
+    }
+}
+
+/// logs written by the container from now on, until it stops
+pub(crate) fn follow_container_logs(id: &str) -> impl Stream<Item = DockerLog> {
+    let docker = docker_client();
+    let since = Utc::now().timestamp();
+    docker
+        .logs(
+            id,
+            Some(LogsOptions::<String> {
+                follow: true,
+                stderr: true,
+                stdout: true,
+                since,
+                timestamps: true,
+                ..Default::default()
+            }),
+        )
+        .filter_map(|chunk| {
+            future::ready(match chunk {
+                Ok(LogOutput::StdOut { message }) => {
+                    parse_message(message).map(|(time, content)| DockerLog {
+                        time,
+                        message: content,
+                        log_type: LogType::Out,
+                    })
+                }
+                Ok(LogOutput::StdErr { message }) => {
+                    parse_message(message).map(|(time, content)| DockerLog {
+                        time,
+                        message: content,
+                        log_type: LogType::Err,
+                    })
+                }
+                _ => None,
+            })
+        })
+}
+
 fn parse_message(message: Bytes) -> Option<(i64, String)> {
     let utf8 = String::from_utf8(message.into()).ok()?;
     let (timestamp, content) = utf8.split_once(" ")?;
@@ -213,56 +271,162 @@ pub(crate) async fn run_container(id: &str) -> Result<(), impl Error> {
         .await
 }
 
+pub(crate) struct BuildOptions {
+    /// path of the Dockerfile inside the build context
+    pub(crate) dockerfile: String,
+    pub(crate) args: EnvVars,
+    /// mounted with `RUN --mount=type=secret,id=<name>` instead of being passed as build args
+    pub(crate) secrets: EnvVars,
+    /// stage of a multi-stage Dockerfile used as the final image
+    pub(crate) target: Option<String>,
+    pub(crate) no_cache: bool,
+}
+
 // #[tracing::instrument]
 pub(crate) async fn build_dockerfile<O: Future<Output = ()> + Send, F: FnMut(BuildInfo) -> O>(
     name: ImageName,
     path: &Path,
-    buildargs: EnvVars,
+    options: BuildOptions,
     process_chunk: &mut F,
 ) -> anyhow::Result<String> {
     // let image_name = nanoid!(21, &alphabet::LOWERCASE_PLUS_NUMBERS);
     let name = name.to_docker_name();
+    let docker = docker_client();
+
+    if options.secrets.is_empty() {
+        build_with_logs(&docker, &name, path, options, process_chunk).await;
+    } else {
+        process_chunk(BuildInfo {
+            stream: Some(
+                "Mounting build secrets, the output of each step is not streamed\n".to_owned(),
+            ),
+            ..Default::default()
+        })
+        .await;
+        build_with_secrets(&docker, &name, path, options).await?;
+    }
 
+    let image = docker.inspect_image(&name).await?;
+    image.id.ok_or(anyhow!("Image not found"))
+}
+
+/// The BuildKit session opened by `build_image` only serves registry credentials, so builds
+/// without secrets go through it to stream the build output
+async fn build_with_logs<O: Future<Output = ()> + Send, F: FnMut(BuildInfo) -> O>(
+    docker: &Docker,
+    name: &str,
+    path: &Path,
+    options: BuildOptions,
+    process_chunk: &mut F,
+) {
     let mut archive_builder = tar::Builder::new(Vec::new());
     archive_builder.append_dir_all(".", path).unwrap();
     let tar_content = archive_builder.into_inner().unwrap();
 
-    let docker = docker_client();
-
+    let mut started = HashSet::new();
     docker
         .build_image(
             BuildImageOptions {
-                t: name.clone(),
-                buildargs: buildargs.into(),
-                rm: true,
-                forcerm: true, // rm intermediate containers even if the build fails
+                t: name.to_owned(),
+                dockerfile: options.dockerfile,
+                buildargs: options.args.into(),
+                nocache: options.no_cache,
+                target: options.target.unwrap_or_default(),
+                version: BuilderVersion::BuilderBuildKit,
+                session: Some(nanoid!(21, &LOWERCASE_PLUS_NUMBERS)),
                 ..Default::default()
             },
             None,
             Some(tar_content.into()),
         )
-        .for_each(|chunk| {
-            let output: Pin<Box<dyn Future<Output = ()> + Send>> = match chunk {
-                // chunk.id // TODO: use this as the image id so I don't have to generate one?
-                //     // chunk.aux // or maybe this
-                Ok(chunk) => Box::pin(process_chunk(chunk)),
-                Err(error) => {
-                    if let DockerError::DockerStreamError { error } = error {
-                        Box::pin(process_chunk(BuildInfo {
-                            error: Some(error),
-                            ..Default::default() // TODO: this is a bit hacky, is this really equivalent
-                        }))
-                    } else {
-                        Box::pin(future::ready(()))
-                    }
-                }
+        .flat_map(|chunk| {
+            let chunks = match chunk {
+                Ok(BuildInfo {
+                    aux: Some(BuildInfoAux::BuildKit(status)),
+                    ..
+                }) => get_buildkit_logs(status, &mut started),
+                Ok(chunk) => vec![chunk],
+                Err(DockerError::DockerStreamError { error }) => vec![BuildInfo {
+                    error: Some(error),
+                    ..Default::default() // TODO: this is a bit hacky, is this really equivalent
+                }],
+                Err(_) => vec![],
             };
-            output
+            stream::iter(chunks)
         })
+        .for_each(process_chunk)
         .await;
+}
 
-    let image = docker.inspect_image(&name).await?;
-    image.id.ok_or(anyhow!("Image not found"))
+/// BuildKit reports the state of every step on each update, so each step is only logged once.
+/// Failures are not taken from the steps, the build stream ends with the same error
+fn get_buildkit_logs(status: StatusResponse, started: &mut HashSet<String>) -> Vec<BuildInfo> {
+    let steps = status.vertexes.into_iter().filter_map(|vertex| {
+        (vertex.started.is_some() && started.insert(vertex.digest)).then(|| BuildInfo {
+            stream: Some(format!("{}\n", vertex.name)),
+            ..Default::default()
+        })
+    });
+    let logs = status.logs.into_iter().map(|log| BuildInfo {
+        stream: Some(String::from_utf8_lossy(&log.msg).into_owned()),
+        ..Default::default()
+    });
+    steps.chain(logs).collect()
+}
+
+/// Secrets are only served by a session the solve request is sent through, which does not
+/// report the progress of the build. The solve future is not Send, so it runs on its own thread,
+/// and dropping the returned future closes the session, cancelling the build
+async fn build_with_secrets(
+    docker: &Docker,
+    name: &str,
+    path: &Path,
+    options: BuildOptions,
+) -> anyhow::Result<()> {
+    // the Dockerfile of an uploaded context is always read from its root
+    if options.dockerfile != "Dockerfile" {
+        let content = fs::read(path.join(&options.dockerfile))?;
+        let root_dockerfile = path.join("Dockerfile");
+        if root_dockerfile.symlink_metadata().is_ok() {
+            fs::remove_file(&root_dockerfile)?;
+        }
+        fs::write(root_dockerfile, content)?;
+    }
+    let mut archive_builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+    archive_builder.append_dir_all(".", path)?;
+    let context = archive_builder.into_inner()?.finish()?;
+
+    let secrets_dir = TempDir::new()?;
+    let mut frontend = ImageBuildFrontendOptions::builder().nocache(options.no_cache);
+    if let Some(target) = &options.target {
+        frontend = frontend.target(target);
+    }
+    for (key, value) in HashMap::<String, String>::from(options.args) {
+        frontend = frontend.buildarg(&key, &value);
+    }
+    for (index, (key, value)) in HashMap::<String, String>::from(options.secrets)
+        .into_iter()
+        .enumerate()
+    {
+        let file = secrets_dir.path().join(index.to_string());
+        fs::write(&file, value)?;
+        frontend = frontend.set_secret(&key, &SecretSource::File(file));
+    }
+
+    let (_cancel, cancelled) = oneshot::channel::<()>();
+    let (docker, name, frontend) = (docker.clone(), name.to_owned(), frontend.build());
+    let runtime = Handle::current();
+    let build = task::spawn_blocking(move || {
+        runtime.block_on(async move {
+            let input = ImageBuildLoadInput::Upload(context.into());
+            let build = Moby::new(&docker).docker_build(&name, frontend, input, None);
+            tokio::select! {
+                result = build => result.map_err(anyhow::Error::from),
+                _ = cancelled => Err(anyhow!("Build cancelled")),
+            }
+        })
+    });
+    build.await?
 }
 
 #[tracing::instrument]
@@ -46,6 +46,10 @@ impl Listener for DockerBridge {
     fn is_public(&self) -> bool {
         true
     }
+
+    async fn container_id(&self) -> Option<String> {
+        None
+    }
 }
 
 // TODO: change to return anyhow::Result
@@ -1,6 +1,11 @@
 use std::{collections::HashMap, ops::Add};
 
-use crate::db::EnvVar;
+use tracing::error;
+
+use crate::{
+    db::{encryption::EnvCipher, nano_id::NanoId, EditedEnvVar, EnvScope, EnvVar},
+    ip_filter::Environment,
+};
 
 #[derive(Debug, Clone, Default)]
 pub(crate) struct EnvVars(HashMap<String, String>);
@@ -13,6 +18,55 @@ impl EnvVars {
     pub(crate) fn empty() -> Self {
         Self(Default::default())
     }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = &String> {
+        self.0.values()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// keeps only the vars available in `phase`, decrypting their values
+    pub(crate) fn scoped(
+        env: &[EnvVar],
+        phase: EnvScope,
+        project: &NanoId,
+        cipher: &EnvCipher,
+    ) -> Self {
+        let vars = env.iter().filter(|var| var.scope.includes(phase));
+        Self::decrypt(vars, project, cipher)
+    }
+
+    /// splits the vars available in the build into the ones also set in the running container,
+    /// which can be build args, and the build only ones, which are mounted as secrets
+    pub(crate) fn build_scoped(
+        env: &[EnvVar],
+        project: &NanoId,
+        cipher: &EnvCipher,
+    ) -> (Self, Self) {
+        let with_scope = |scope| env.iter().filter(move |var| var.scope == scope);
+        (
+            Self::decrypt(with_scope(EnvScope::Both), project, cipher),
+            Self::decrypt(with_scope(EnvScope::Build), project, cipher),
+        )
+    }
+
+    fn decrypt<'a>(
+        vars: impl Iterator<Item = &'a EnvVar>,
+        project: &NanoId,
+        cipher: &EnvCipher,
+    ) -> Self {
+        let decrypted =
+            vars.filter_map(|var| match cipher.decrypt(project, &var.name, &var.value) {
+                Ok(value) => Some((var.name.clone(), value)),
+                Err(error) => {
+                    error!("skipping env var {}: {error}", var.name);
+                    None
+                }
+            });
+        Self(decrypted.collect())
+    }
 }
 
 impl IntoIterator for EnvVars {
@@ -32,13 +86,6 @@ impl From<EnvVars> for HashMap<String, String> {
     }
 }
 
-impl From<Vec<EnvVar>> for EnvVars {
-    fn from(value: Vec<EnvVar>) -> Self {
-        let map = value.into_iter().map(|env| (env.name, env.value));
-        Self(map.collect())
-    }
-}
-
 impl From<HashMap<String, String>> for EnvVars {
     fn from(value: HashMap<String, String>) -> Self {
         Self(value)
@@ -74,3 +121,128 @@ impl Add for EnvVars {
         Self(self.0.into_iter().chain(other.0).collect())
     }
 }
+
+/// Branch specific vars only apply to previews, so they are stored as such. Returns false if the
+/// var targets a branch in production
+pub(crate) fn normalize_env_target(var: &mut EnvVar) -> bool {
+    match &var.branch {
+        Some(branch) if branch.is_empty() => false,
+        Some(_) if var.environment == Some(Environment::Production) => false,
+        Some(_) => {
+            var.environment = Some(Environment::Preview);
+            true
+        }
+        None => true,
+    }
+}
+
+/// Picks the value of each var that applies to a deployment. Branch specific values win over
+/// environment specific ones, which win over the ones for every environment
+pub(crate) fn resolve_deployment_env(
+    env: &[EditedEnvVar],
+    branch: &str,
+    default_branch: bool,
+) -> Vec<EditedEnvVar> {
+    let environment = if default_branch {
+        Environment::Production
+    } else {
+        Environment::Preview
+    };
+    let mut resolved: HashMap<&str, &EditedEnvVar> = HashMap::new();
+    for var in env.iter().filter(|var| applies(var, environment, branch)) {
+        match resolved.get(var.name.as_str()) {
+            Some(current) if precedence(current) >= precedence(var) => {}
+            _ => {
+                resolved.insert(&var.name, var);
+            }
+        }
+    }
+    resolved.into_values().cloned().collect()
+}
+
+fn applies(var: &EditedEnvVar, environment: Environment, branch: &str) -> bool {
+    let environment_matches = var.environment.map_or(true, |target| target == environment);
+    let branch_matches = var.branch.as_ref().map_or(true, |pattern| {
+        environment == Environment::Preview && matches_branch(pattern, branch)
+    });
+    environment_matches && branch_matches
+}
+
+/// exact branch names go first, then the longest patterns
+fn precedence(var: &EditedEnvVar) -> (bool, bool, usize, bool) {
+    let branch = var.branch.as_deref();
+    (
+        branch.is_some(),
+        branch.is_some_and(|branch| !branch.contains('*')),
+        branch.map_or(0, str::len),
+        var.environment.is_some(),
+    )
+}
+
+/// `*` matches any sequence of characters, including `/`
+fn matches_branch(pattern: &str, branch: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = branch.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod env_tests {
+    use super::{matches_branch, resolve_deployment_env};
+    use crate::{
+        db::{EditedEnvVar, EnvScope},
+        ip_filter::Environment,
+    };
+
+    fn var(value: &str, environment: Option<Environment>, branch: Option<&str>) -> EditedEnvVar {
+        EditedEnvVar {
+            name: "API_URL".to_owned(),
+            value: value.to_owned(),
+            edited: 0,
+            scope: EnvScope::Both,
+            environment,
+            branch: branch.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn test_matches_branch() {
+        assert!(matches_branch("main", "main"));
+        assert!(!matches_branch("main", "main-2"));
+        assert!(matches_branch("feat/*", "feat/login/form"));
+        assert!(matches_branch("*-fix", "login-fix"));
+        assert!(!matches_branch("a*a", "a"));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let env = [
+            var("all", None, None),
+            var("preview", Some(Environment::Preview), None),
+            var("feat", Some(Environment::Preview), Some("feat/*")),
+            var("login", Some(Environment::Preview), Some("feat/login")),
+        ];
+        let value = |branch, default_branch| {
+            resolve_deployment_env(&env, branch, default_branch)[0]
+                .value
+                .clone()
+        };
+        assert_eq!(value("main", true), "all");
+        assert_eq!(value("fix", false), "preview");
+        assert_eq!(value("feat/signup", false), "feat");
+        assert_eq!(value("feat/login", false), "login");
+    }
+}
@@ -120,27 +120,6 @@ impl Github {
         Ok(())
     }
 
-    #[tracing::instrument]
-    pub(crate) async fn download_file(
-        &self,
-        repo_id: i64,
-        sha: &str,
-        path: &str,
-    ) -> anyhow::Result<String> {
-        let crab = self.get_crab(repo_id).await?;
-        let (owner, name) = self.get_owner_and_name(repo_id).await?;
-        let mut contents = crab
-            .repos(owner, name)
-            .get_content()
-            .path(path)
-            .r#ref(sha)
-            .send()
-            .await?;
-        let content = contents.take_items().pop().ok_or(anyhow!("no content"))?;
-        let decoded = content.decoded_content();
-        decoded.ok_or(anyhow!("invalid content"))
-    }
-
     // TODO: make this receive crab as argument
     #[tracing::instrument]
     async fn get_owner_and_name(&self, id: i64) -> anyhow::Result<(String, String)> {
@@ -2,6 +2,7 @@ use std::{collections::HashMap, fmt};
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use octocrab::{
     models::issues::Comment,
     params::checks::{CheckRunConclusion, CheckRunStatus},
@@ -10,9 +11,14 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     conf::Conf,
-    db::{nano_id::NanoId, BuildResult, Db},
+    db::{nano_id::NanoId, BuildResult, Db, DeploymentEventKind},
+    docker::follow_container_logs,
     github::Github,
+    log_drain::{LogDrains, LogSource},
+    logging::Log,
+    notifications::{notify, NotificationEvent},
     provider,
+    redact::Redactor,
     tokens::{decode_token, generate_token},
     utils::now,
 };
@@ -20,9 +26,17 @@ use crate::{
 #[async_trait]
 pub(crate) trait DeploymentHooks: 'static + Send + Sync + fmt::Debug {
     async fn on_build_log(&self, output: &str, error: bool);
+    async fn on_config_read(&self, config: &str);
     async fn on_build_started(&self);
-    async fn on_build_finished(&self);
+    async fn on_build_finished(&self, image: &str);
     async fn on_build_failed(&self);
+    /// the build was cancelled or skipped
+    async fn on_build_stopped(&self, result: BuildResult);
+    async fn on_queued(&self);
+    async fn on_container_starting(&self);
+    async fn on_container_started(&self, container: &str);
+    async fn on_container_downgraded(&self);
+    async fn on_container_crashed(&self, exit_code: Option<i64>);
 }
 
 #[derive(Debug)]
@@ -31,9 +45,16 @@ pub(crate) struct NoopHooks;
 #[async_trait]
 impl DeploymentHooks for NoopHooks {
     async fn on_build_log(&self, _output: &str, _error: bool) {}
+    async fn on_config_read(&self, _config: &str) {}
     async fn on_build_started(&self) {}
-    async fn on_build_finished(&self) {}
+    async fn on_build_finished(&self, _image: &str) {}
     async fn on_build_failed(&self) {}
+    async fn on_build_stopped(&self, _result: BuildResult) {}
+    async fn on_queued(&self) {}
+    async fn on_container_starting(&self) {}
+    async fn on_container_started(&self, _container: &str) {}
+    async fn on_container_downgraded(&self) {}
+    async fn on_container_crashed(&self, _exit_code: Option<i64>) {}
 }
 
 #[derive(Debug, Clone)]
@@ -41,40 +62,68 @@ pub(crate) struct StatusHooks {
     db: Db,
     id: NanoId,
     github: Github,
+    log_drains: LogDrains,
+    redactor: Redactor,
 }
 
 impl StatusHooks {
-    pub(crate) fn new(deployment_id: NanoId, db: Db, github: Github) -> Self {
+    pub(crate) fn new(
+        deployment_id: NanoId,
+        db: Db,
+        github: Github,
+        log_drains: LogDrains,
+    ) -> Self {
         Self {
             db,
             id: deployment_id,
             github,
+            log_drains,
+            redactor: Default::default(),
         }
     }
+
+    pub(crate) fn with_redactor(self, redactor: Redactor) -> Self {
+        Self { redactor, ..self }
+    }
 }
 
 // TODO: write also error status to db, and send updates to github!!
 #[async_trait]
 impl DeploymentHooks for StatusHooks {
     async fn on_build_log(&self, output: &str, error: bool) {
+        let output = &self.redactor.redact(output);
         self.db
             .insert_deployment_build_log(&self.id, output, error) // TODO: differentiate error logs
             .await;
+        let log = Log::from_build_output(now(), &self.id, output, error);
+        self.log_drains.send(LogSource::Build, log);
+    }
+
+    async fn on_config_read(&self, config: &str) {
+        self.db.update_deployment_config(&self.id, config).await;
     }
 
     async fn on_build_started(&self) {
         self.db.clear_deployment_build_logs(&self.id).await;
         self.db.update_deployment_build_start(&self.id, now()).await;
         self.db.reset_deployment_build_end(&self.id).await;
+        self.db.update_deployment_image(&self.id, None).await;
+        self.record_event(DeploymentEventKind::BuildStarted, None)
+            .await;
         self.update_github(Status::Building);
+        notify(&self.db, NotificationEvent::BuildStarted, &self.id, None);
     }
 
-    async fn on_build_finished(&self) {
+    async fn on_build_finished(&self, image: &str) {
         self.db.update_deployment_build_end(&self.id, now()).await;
+        self.db.update_deployment_image(&self.id, Some(image)).await;
         self.db
             .update_deployment_result(&self.id, BuildResult::Built) // FIXME: the db should maybe only have a flag error: bool
             .await;
+        self.record_event(DeploymentEventKind::BuildFinished, None)
+            .await;
         self.update_github(Status::Ready);
+        notify(&self.db, NotificationEvent::DeploymentReady, &self.id, None);
     }
 
     async fn on_build_failed(&self) {
@@ -82,7 +131,70 @@ impl DeploymentHooks for StatusHooks {
         self.db
             .update_deployment_result(&self.id, BuildResult::Failed)
             .await;
+        self.record_event(DeploymentEventKind::BuildFailed, None)
+            .await;
         self.update_github(Status::Failed);
+        notify(&self.db, NotificationEvent::BuildFailed, &self.id, None);
+    }
+
+    async fn on_build_stopped(&self, result: BuildResult) {
+        self.db.update_deployment_build_end(&self.id, now()).await;
+        self.db.update_deployment_result(&self.id, result).await;
+        let (event, status) = match result {
+            BuildResult::Skipped => (DeploymentEventKind::BuildSkipped, Status::Skipped),
+            _ => (DeploymentEventKind::BuildCancelled, Status::Cancelled),
+        };
+        self.record_event(event, None).await;
+        self.update_github(status);
+    }
+
+    async fn on_queued(&self) {
+        self.record_event(DeploymentEventKind::Queued, None).await;
+    }
+
+    async fn on_container_starting(&self) {
+        self.record_event(DeploymentEventKind::ContainerStarting, None)
+            .await;
+    }
+
+    async fn on_container_started(&self, container: &str) {
+        self.record_event(DeploymentEventKind::ContainerStarted, Some(container))
+            .await;
+        // drains added while the container is running only get its logs after a restart
+        let has_drains = self
+            .db
+            .get_deployment_with_project(&self.id)
+            .await
+            .is_some_and(|deployment| !deployment.project.log_drains.is_empty());
+        if has_drains {
+            let mut logs = Box::pin(follow_container_logs(container));
+            let id = self.id.clone();
+            let log_drains = self.log_drains.clone();
+            let redactor = self.redactor.clone();
+            tokio::spawn(async move {
+                while let Some(mut log) = logs.next().await {
+                    log.message = redactor.redact(&log.message);
+                    log_drains.send(LogSource::App, Log::from_docker(log, id.clone()));
+                }
+            });
+        }
+    }
+
+    async fn on_container_downgraded(&self) {
+        self.record_event(DeploymentEventKind::Downgraded, None)
+            .await;
+    }
+
+    async fn on_container_crashed(&self, exit_code: Option<i64>) {
+        let detail = exit_code.map(|code| format!("exit code {code}"));
+        self.record_event(DeploymentEventKind::Crashed, detail.as_deref())
+            .await;
+        notify(
+            &self.db,
+            NotificationEvent::ContainerCrashed,
+            &self.id,
+            exit_code,
+        );
     }
 }
 
@@ -91,6 +203,8 @@ enum Status {
     Building,
     Ready,
     Failed,
+    Cancelled,
+    Skipped,
 }
 
 impl From<Status> for CheckRunStatus {
@@ -99,11 +213,19 @@ impl From<Status> for CheckRunStatus {
             Status::Building => Self::InProgress,
             Status::Ready => Self::Completed,
             Status::Failed => Self::Completed,
+            Status::Cancelled => Self::Completed,
+            Status::Skipped => Self::Completed,
         }
     }
 }
 
 impl StatusHooks {
+    async fn record_event(&self, event: DeploymentEventKind, detail: Option<&str>) {
+        self.db
+            .insert_deployment_event(&self.id, event, detail)
+            .await;
+    }
+
     // TODO: record updated time before the async code and check it to avoid overwriting a newer comment
     fn update_github(&self, status: Status) {
         let hooks = self.clone();
@@ -113,6 +235,7 @@ impl StatusHooks {
                 hostname,
                 provider,
                 secret,
+                ..
             } = Conf::read_async().await; // FIXME: this should be async
             let deployment = hooks
                 .db
@@ -164,6 +287,13 @@ impl StatusHooks {
                         Status::Failed => {
                             (CheckRunStatus::Completed, Some(CheckRunConclusion::Failure))
                         }
+                        Status::Cancelled => (
+                            CheckRunStatus::Completed,
+                            Some(CheckRunConclusion::Cancelled),
+                        ),
+                        Status::Skipped => {
+                            (CheckRunStatus::Completed, Some(CheckRunConclusion::Skipped))
+                        }
                     };
                     let check_name = format!("Prezel - {project_name}");
                     let _ = bot
@@ -205,6 +335,8 @@ fn create_comment(info: GithubCommentInfo, secret: &str) -> String {
             Status::Building => "🔨 Building",
             Status::Ready => "✅ Ready",
             Status::Failed => "❌ Failed",
+            Status::Cancelled => "🚫 Cancelled",
+            Status::Skipped => "⏭️ Skipped",
         };
         let updated = updated.format("%b %e, %Y %l:%M%P").to_string();
         format!("| **{name}** | {formatted_status} ([Inspect]({provider_url})) | [Visit Preview]({preview_url}) | {updated} |")
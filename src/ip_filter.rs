@@ -0,0 +1,191 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum IpAction {
+    Allow,
+    Deny,
+}
+
+impl IpAction {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Allow => "allow",
+            Self::Deny => "deny",
+        }
+    }
+
+    pub(crate) fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "allow" => Some(Self::Allow),
+            "deny" => Some(Self::Deny),
+            _ => None,
+        }
+    }
+}
+
+/// Production covers the deployments from the default branch, preview everything else
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Environment {
+    Production,
+    Preview,
+}
+
+impl Environment {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Production => "production",
+            Self::Preview => "preview",
+        }
+    }
+
+    pub(crate) fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "production" => Some(Self::Production),
+            "preview" => Some(Self::Preview),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub(crate) struct IpRule {
+    /// CIDR range, a single address is also accepted
+    #[serde(deserialize_with = "deserialize_cidr")]
+    #[schema(value_type = String, example = "10.0.0.0/8")]
+    pub(crate) cidr: IpNet,
+    pub(crate) action: IpAction,
+    /// the rule applies to every environment if not set
+    pub(crate) environment: Option<Environment>,
+}
+
+pub(crate) fn parse_cidr(value: &str) -> Option<IpNet> {
+    value
+        .parse()
+        .ok()
+        .or_else(|| value.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+fn deserialize_cidr<'de, D: Deserializer<'de>>(deserializer: D) -> Result<IpNet, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_cidr(&value).ok_or_else(|| serde::de::Error::custom(format!("invalid CIDR: {value}")))
+}
+
+/// Deny rules always win. If there is any allow rule, the address needs to match one of them
+pub(crate) fn is_ip_allowed(rules: &[IpRule], environment: Environment, ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    let mut allow_list = false;
+    let mut allowed = false;
+    for rule in rules
+        .iter()
+        .filter(|rule| rule.environment.map_or(true, |env| env == environment))
+    {
+        let matches = rule.cidr.contains(&ip);
+        match rule.action {
+            IpAction::Deny if matches => return false,
+            IpAction::Deny => {}
+            IpAction::Allow => {
+                allow_list = true;
+                allowed |= matches;
+            }
+        }
+    }
+    !allow_list || allowed
+}
+
+/// X-Forwarded-For is only honoured if the peer is a trusted proxy. In that case the header is
+/// walked from right to left, and the first address not belonging to a trusted proxy is the client
+pub(crate) fn get_client_ip(
+    peer: IpAddr,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpNet],
+) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    let mut client = peer.to_canonical();
+    if let Some(forwarded_for) = forwarded_for {
+        for hop in forwarded_for.rsplit(',') {
+            if !is_trusted(&client) {
+                break;
+            }
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) => client = ip.to_canonical(),
+                Err(_) => break,
+            }
+        }
+    }
+    client
+}
+
+/// value of X-Forwarded-For for upstream requests. The incoming header is only extended if the
+/// peer is a trusted proxy, otherwise it gets replaced so clients can't spoof it
+pub(crate) fn get_forwarded_for(
+    peer: IpAddr,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpNet],
+) -> String {
+    let peer = peer.to_canonical();
+    let trusted = trusted_proxies.iter().any(|net| net.contains(&peer));
+    match forwarded_for {
+        Some(forwarded_for) if trusted => format!("{forwarded_for}, {peer}"),
+        _ => peer.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod ip_filter_tests {
+    use super::{get_client_ip, is_ip_allowed, parse_cidr, Environment, IpAction, IpRule};
+
+    fn rule(cidr: &str, action: IpAction, environment: Option<Environment>) -> IpRule {
+        IpRule {
+            cidr: parse_cidr(cidr).unwrap(),
+            action,
+            environment,
+        }
+    }
+
+    #[test]
+    fn test_allow_and_deny_lists() {
+        let rules = vec![
+            rule("10.0.0.0/8", IpAction::Allow, Some(Environment::Preview)),
+            rule("10.0.0.1", IpAction::Deny, None),
+        ];
+        let preview = Environment::Preview;
+        assert!(is_ip_allowed(&rules, preview, "10.1.2.3".parse().unwrap()));
+        assert!(!is_ip_allowed(&rules, preview, "10.0.0.1".parse().unwrap()));
+        assert!(!is_ip_allowed(&rules, preview, "8.8.8.8".parse().unwrap()));
+        assert!(!is_ip_allowed(
+            &rules,
+            preview,
+            "::ffff:10.0.0.1".parse().unwrap()
+        ));
+
+        let production = Environment::Production;
+        assert!(is_ip_allowed(
+            &rules,
+            production,
+            "8.8.8.8".parse().unwrap()
+        ));
+        assert!(!is_ip_allowed(
+            &rules,
+            production,
+            "10.0.0.1".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_forwarded_for_from_trusted_proxies_only() {
+        let trusted = vec![parse_cidr("172.16.0.0/12").unwrap()];
+        let header = Some("1.1.1.1, 2.2.2.2, 172.16.0.5");
+
+        let from_proxy = get_client_ip("172.16.0.1".parse().unwrap(), header, &trusted);
+        assert_eq!(from_proxy, "2.2.2.2".parse::<std::net::IpAddr>().unwrap());
+
+        let spoofed = get_client_ip("3.3.3.3".parse().unwrap(), header, &trusted);
+        assert_eq!(spoofed, "3.3.3.3".parse::<std::net::IpAddr>().unwrap());
+    }
+}
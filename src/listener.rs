@@ -17,4 +17,6 @@ impl From<SocketAddrV4> for Access {
 pub(crate) trait Listener: Send {
     async fn access(&self) -> anyhow::Result<Access>;
     fn is_public(&self) -> bool;
+    /// id of the docker container currently running, if any
+    async fn container_id(&self) -> Option<String>;
 }
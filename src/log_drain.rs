@@ -0,0 +1,474 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use anyhow::{anyhow, ensure};
+use chrono::{DateTime, SecondsFormat};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
+    sync::mpsc::{self, error::TrySendError, Receiver, Sender},
+    time::{sleep, timeout_at, Instant},
+};
+use tracing::{error, warn};
+use url::Url;
+use utoipa::ToSchema;
+
+use crate::{
+    db::{nano_id::NanoId, Db},
+    logging::{Level, Log},
+    metrics::metrics,
+};
+
+/// records waiting to be dispatched, anything above is dropped
+const QUEUE_SIZE: usize = 10_000;
+/// records waiting to be sent to each drain, anything above is dropped
+const DRAIN_QUEUE_SIZE: usize = 1_000;
+const BATCH_SIZE: usize = 100;
+/// max time a record waits for the batch to fill up
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// drains of a project and the project of a deployment are reloaded from the db after this long
+const CONFIG_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SyslogProtocol {
+    Tcp,
+    Udp,
+}
+
+/// External sink receiving the logs of a project
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, ToSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum LogDrain {
+    /// batches of records POSTed as newline delimited JSON
+    Http {
+        url: String,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+    /// RFC 5424 messages, octet counted when using TCP
+    Syslog {
+        /// host:port
+        address: String,
+        protocol: SyslogProtocol,
+    },
+    /// OTLP logs over HTTP with JSON encoding, sent to {endpoint}/v1/logs
+    Otlp {
+        endpoint: String,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+}
+
+impl LogDrain {
+    pub(crate) fn is_valid(&self) -> bool {
+        match self {
+            Self::Http { url, .. } | Self::Otlp { endpoint: url, .. } => {
+                Url::parse(url).is_ok_and(|url| url.scheme() == "http" || url.scheme() == "https")
+            }
+            Self::Syslog { address, .. } => address
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogSource {
+    Request,
+    App,
+    Build,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct DrainRecord {
+    project: String,
+    source: LogSource,
+    #[serde(flatten)]
+    log: Log,
+}
+
+/// Forwards logs to the drains of their project without ever blocking the caller
+#[derive(Clone, Debug)]
+pub(crate) struct LogDrains {
+    sender: Sender<(LogSource, Log)>,
+}
+
+impl LogDrains {
+    pub(crate) fn start(db: Db, hostname: String) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(dispatch(db, hostname, receiver));
+        Self { sender }
+    }
+
+    /// the record is dropped if the queue is full
+    pub(crate) fn send(&self, source: LogSource, log: Log) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send((source, log)) {
+            metrics().record_dropped_log();
+        }
+    }
+}
+
+struct ProjectDrains {
+    loaded: Instant,
+    name: String,
+    workers: Vec<(LogDrain, Sender<DrainRecord>)>,
+}
+
+async fn dispatch(db: Db, hostname: String, mut receiver: Receiver<(LogSource, Log)>) {
+    let mut deployments: HashMap<String, (Instant, Option<NanoId>)> = HashMap::new();
+    let mut projects: HashMap<NanoId, ProjectDrains> = HashMap::new();
+    while let Some((source, log)) = receiver.recv().await {
+        let cached = deployments
+            .get(&log.deployment)
+            .filter(|(loaded, _)| loaded.elapsed() <= CONFIG_TTL);
+        let project = match cached {
+            Some((_, project)) => project.clone(),
+            None => {
+                // deleted deployments would otherwise stay in the cache forever
+                deployments.retain(|_, (loaded, _)| loaded.elapsed() <= CONFIG_TTL);
+                let id = log.deployment.clone().into();
+                let project = db.get_deployment(&id).await.map(|info| info.project);
+                deployments.insert(log.deployment.clone(), (Instant::now(), project.clone()));
+                project
+            }
+        };
+        let Some(project) = project else {
+            continue;
+        };
+
+        let outdated = projects
+            .get(&project)
+            .map_or(true, |drains| drains.loaded.elapsed() > CONFIG_TTL);
+        if outdated {
+            let Some(info) = db.get_project(&project).await else {
+                projects.remove(&project);
+                continue;
+            };
+            let mut previous = projects
+                .remove(&project)
+                .map(|drains| drains.workers)
+                .unwrap_or_default();
+            let workers = info
+                .log_drains
+                .into_iter()
+                .map(|drain| {
+                    // keep the workers that are still configured so their pending batches are not lost
+                    let existing = previous.iter().position(|(current, _)| current == &drain);
+                    let sender = match existing {
+                        Some(index) => previous.swap_remove(index).1,
+                        None => DrainWorker::spawn(drain.clone(), hostname.clone()),
+                    };
+                    (drain, sender)
+                })
+                .collect();
+            let drains = ProjectDrains {
+                loaded: Instant::now(),
+                name: info.name,
+                workers,
+            };
+            projects.insert(project.clone(), drains);
+        }
+
+        if let Some(drains) = projects.get(&project) {
+            let record = DrainRecord {
+                project: drains.name.clone(),
+                source,
+                log,
+            };
+            for (_, sender) in &drains.workers {
+                if sender.try_send(record.clone()).is_err() {
+                    metrics().record_dropped_log();
+                }
+            }
+        }
+    }
+}
+
+struct DrainWorker {
+    drain: LogDrain,
+    hostname: String,
+    client: reqwest::Client,
+}
+
+impl DrainWorker {
+    fn spawn(drain: LogDrain, hostname: String) -> Sender<DrainRecord> {
+        let (sender, receiver) = mpsc::channel(DRAIN_QUEUE_SIZE);
+        let worker = Self {
+            drain,
+            hostname,
+            client: reqwest::Client::new(),
+        };
+        tokio::spawn(worker.run(receiver));
+        sender
+    }
+
+    /// finishes once the drain is removed and every pending record has been delivered
+    async fn run(self, mut receiver: Receiver<DrainRecord>) {
+        while let Some(first) = receiver.recv().await {
+            let mut batch = vec![first];
+            let deadline = Instant::now() + BATCH_INTERVAL;
+            while batch.len() < BATCH_SIZE {
+                match timeout_at(deadline, receiver.recv()).await {
+                    Ok(Some(record)) => batch.push(record),
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            self.deliver(&batch).await;
+        }
+    }
+
+    /// while retrying, new records pile up in the queue and get dropped once it is full
+    async fn deliver(&self, batch: &[DrainRecord]) {
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let result = tokio::time::timeout(SEND_TIMEOUT, self.send(batch)).await;
+            match result.unwrap_or_else(|_| Err(anyhow!("timed out"))) {
+                Ok(()) => return,
+                Err(error) if attempt < MAX_ATTEMPTS => {
+                    warn!("failed to send logs to {:?}, retrying: {error}", self.drain);
+                    sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(error) => {
+                    error!(
+                        "dropping {} logs for {:?}: {error}",
+                        batch.len(),
+                        self.drain
+                    );
+                    for _ in batch {
+                        metrics().record_dropped_log();
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send(&self, batch: &[DrainRecord]) -> anyhow::Result<()> {
+        match &self.drain {
+            LogDrain::Http { url, headers } => {
+                let body: String = batch
+                    .iter()
+                    .map(|record| serde_json::to_string(record).unwrap() + "\n")
+                    .collect();
+                let request = self
+                    .client
+                    .post(url)
+                    .header("Content-Type", "application/x-ndjson");
+                let response = with_headers(request, headers).body(body).send().await?;
+                ensure!(response.status().is_success(), "got {}", response.status());
+            }
+            LogDrain::Syslog { address, protocol } => {
+                let messages = batch
+                    .iter()
+                    .map(|record| format_syslog(record, &self.hostname));
+                match protocol {
+                    SyslogProtocol::Tcp => {
+                        let mut stream = TcpStream::connect(address).await?;
+                        for message in messages {
+                            let frame = format!("{} {message}", message.len());
+                            stream.write_all(frame.as_bytes()).await?;
+                        }
+                        stream.flush().await?;
+                    }
+                    SyslogProtocol::Udp => {
+                        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                        socket.connect(address).await?;
+                        for message in messages {
+                            socket.send(message.as_bytes()).await?;
+                        }
+                    }
+                }
+            }
+            LogDrain::Otlp { endpoint, headers } => {
+                let url = format!("{}/v1/logs", endpoint.trim_end_matches('/'));
+                let request = self.client.post(url).json(&format_otlp(batch));
+                let response = with_headers(request, headers).send().await?;
+                ensure!(response.status().is_success(), "got {}", response.status());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn with_headers(
+    request: reqwest::RequestBuilder,
+    headers: &BTreeMap<String, String>,
+) -> reqwest::RequestBuilder {
+    headers.iter().fold(request, |request, (name, value)| {
+        request.header(name, value)
+    })
+}
+
+/// one line summary, the full record is only available for HTTP drains
+fn get_message(log: &Log) -> String {
+    match (&log.method, &log.host, &log.path, log.status) {
+        (Some(method), Some(host), Some(path), Some(status)) => {
+            let duration = log.request.as_ref().map_or(0, |request| request.duration);
+            format!("{method} {host}{path} {status} {duration}ms")
+        }
+        _ => log.message.clone().unwrap_or_default(),
+    }
+}
+
+fn format_syslog(record: &DrainRecord, hostname: &str) -> String {
+    // facility user (1) and severity informational (6) or error (3)
+    let severity = if record.log.level == Level::ERROR {
+        3
+    } else {
+        6
+    };
+    let priority = 8 + severity;
+    let timestamp = DateTime::from_timestamp_millis(record.log.time)
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::Millis, true))
+        .unwrap_or("-".to_owned());
+    let app_name = to_syslog_field(&record.project, 48);
+    let proc_id = to_syslog_field(&record.log.deployment, 128);
+    let msg_id = match record.source {
+        LogSource::Request => "request",
+        LogSource::App => "app",
+        LogSource::Build => "build",
+    };
+    let message = get_message(&record.log);
+    format!("<{priority}>1 {timestamp} {hostname} {app_name} {proc_id} {msg_id} - {message}")
+}
+
+/// header fields only allow printable ASCII without spaces
+fn to_syslog_field(value: &str, max_len: usize) -> String {
+    let field: String = value
+        .chars()
+        .filter(|char| char.is_ascii_graphic())
+        .take(max_len)
+        .collect();
+    if field.is_empty() {
+        "-".to_owned()
+    } else {
+        field
+    }
+}
+
+fn format_otlp(batch: &[DrainRecord]) -> Value {
+    let mut by_project: BTreeMap<&str, Vec<Value>> = BTreeMap::new();
+    for record in batch {
+        let log = &record.log;
+        let (severity_number, severity_text) = match log.level {
+            Level::INFO => (9, "INFO"),
+            Level::ERROR => (17, "ERROR"),
+        };
+        let mut attributes = vec![
+            otlp_attribute("prezel.deployment", json!(log.deployment)),
+            otlp_attribute("prezel.source", json!(record.source)),
+        ];
+        let optional = [
+            (
+                "http.request.method",
+                log.method.as_ref().map(|value| json!(value)),
+            ),
+            (
+                "server.address",
+                log.host.as_ref().map(|value| json!(value)),
+            ),
+            ("url.path", log.path.as_ref().map(|value| json!(value))),
+            (
+                "http.response.status_code",
+                log.status.map(|value| json!(value)),
+            ),
+            (
+                "prezel.request_id",
+                log.request_id.as_ref().map(|value| json!(value)),
+            ),
+        ];
+        attributes.extend(
+            optional
+                .into_iter()
+                .filter_map(|(key, value)| Some(otlp_attribute(key, value?))),
+        );
+        by_project.entry(&record.project).or_default().push(json!({
+            "timeUnixNano": (log.time as i128 * 1_000_000).to_string(),
+            "severityNumber": severity_number,
+            "severityText": severity_text,
+            "body": { "stringValue": get_message(log) },
+            "attributes": attributes,
+        }));
+    }
+    let resource_logs: Vec<_> = by_project
+        .into_iter()
+        .map(|(project, records)| {
+            json!({
+                "resource": {
+                    "attributes": [otlp_attribute("service.name", json!(project))],
+                },
+                "scopeLogs": [{
+                    "scope": { "name": "prezel" },
+                    "logRecords": records,
+                }],
+            })
+        })
+        .collect();
+    json!({ "resourceLogs": resource_logs })
+}
+
+fn otlp_attribute(key: &str, value: Value) -> Value {
+    let value = match value {
+        Value::Number(number) => json!({ "intValue": number.to_string() }),
+        Value::String(string) => json!({ "stringValue": string }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+#[cfg(test)]
+mod log_drain_tests {
+    use crate::logging::{Level, Log};
+
+    use super::{format_syslog, DrainRecord, LogDrain, LogSource, SyslogProtocol};
+
+    #[test]
+    fn test_syslog_format() {
+        let record = DrainRecord {
+            project: "my app".to_owned(),
+            source: LogSource::App,
+            log: Log {
+                time: 1_700_000_000_123,
+                level: Level::ERROR,
+                deployment: "abc".to_owned(),
+                host: None,
+                method: None,
+                path: None,
+                status: None,
+                message: Some("boom".to_owned()),
+                request_id: None,
+                request: None,
+            },
+        };
+        assert_eq!(
+            format_syslog(&record, "example.com"),
+            "<11>1 2023-11-14T22:13:20.123Z example.com myapp abc app - boom"
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        let syslog = |address: &str| LogDrain::Syslog {
+            address: address.to_owned(),
+            protocol: SyslogProtocol::Udp,
+        };
+        assert!(syslog("logs.example.com:514").is_valid());
+        assert!(!syslog("logs.example.com").is_valid());
+        assert!(!syslog(":514").is_valid());
+        let http = |url: &str| LogDrain::Http {
+            url: url.to_owned(),
+            headers: Default::default(),
+        };
+        assert!(http("https://logs.example.com/ingest").is_valid());
+        assert!(!http("ftp://logs.example.com").is_valid());
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
-    fs::{self, File},
+    fs,
     io::{self, Write},
+    net::IpAddr,
     path::Path,
     sync::mpsc::{self, Sender},
     thread::{self, JoinHandle},
@@ -22,13 +23,23 @@ use crate::{
 
 const LOG_FILE_PREFIX: &str = "log";
 
-#[derive(Serialize, Deserialize, ToSchema)]
+// Every record is written as: magic (2 bytes), version (1 byte), payload length (u32 LE),
+// crc32 of the payload (u32 LE), and the payload itself, i.e. bincode of the RequestLog for
+// that version. Changing RequestLog requires bumping RECORD_VERSION and keeping a copy of the
+// previous struct so it can still be decoded in decode_payload
+const RECORD_MAGIC: [u8; 2] = *b"PZ";
+const RECORD_VERSION: u8 = 1;
+const RECORD_HEADER_LEN: usize = 11;
+/// anything longer than this is considered corrupt
+const MAX_RECORD_LEN: usize = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
 pub(crate) enum Level {
     INFO,
     ERROR,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub(crate) struct RequestLog {
     pub(crate) time: i64,
     pub(crate) level: Level,
@@ -36,11 +47,66 @@ pub(crate) struct RequestLog {
     pub(crate) host: String,
     pub(crate) method: String, // TODO: make enum out of this?
     pub(crate) path: String,
+    pub(crate) query: Option<String>,
     pub(crate) status: u16,
     // pub(crate) message: String,
+    pub(crate) request_id: String,
+    /// milliseconds since the request was received until it was fully answered
+    pub(crate) duration: u64,
+    /// milliseconds until the response headers from the app arrived, if the request reached it
+    pub(crate) upstream_duration: Option<u64>,
+    pub(crate) request_bytes: u64,
+    pub(crate) response_bytes: u64,
+    pub(crate) client_ip: Option<IpAddr>,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) referer: Option<String>,
+    /// docker container that served the request
+    pub(crate) container: Option<String>,
+    /// the container was not running and the request had to wait for it to start or build
+    pub(crate) cold_start: bool,
+    /// Cache-Status or X-Cache header returned by the app
+    pub(crate) cache_status: Option<String>,
 }
 
-#[derive(Serialize, ToSchema)]
+/// Format used before the records were framed, only read to migrate old files
+#[derive(Deserialize)]
+struct LegacyRequestLog {
+    time: i64,
+    level: Level,
+    deployment: NanoId,
+    host: String,
+    method: String,
+    path: String,
+    status: u16,
+}
+
+impl From<LegacyRequestLog> for RequestLog {
+    fn from(value: LegacyRequestLog) -> Self {
+        Self {
+            time: value.time,
+            level: value.level,
+            deployment: value.deployment,
+            host: value.host,
+            method: value.method,
+            path: value.path,
+            query: None,
+            status: value.status,
+            request_id: String::new(),
+            duration: 0,
+            upstream_duration: None,
+            request_bytes: 0,
+            response_bytes: 0,
+            client_ip: None,
+            user_agent: None,
+            referer: None,
+            container: None,
+            cold_start: false,
+            cache_status: None,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, ToSchema)]
 pub(crate) struct Log {
     pub(crate) time: i64,
     pub(crate) level: Level,
@@ -50,6 +116,26 @@ pub(crate) struct Log {
     pub(crate) path: Option<String>,
     pub(crate) status: Option<u16>,
     pub(crate) message: Option<String>,
+    pub(crate) request_id: Option<String>,
+    pub(crate) request: Option<RequestDetails>,
+}
+
+/// Extra information only available for request logs
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub(crate) struct RequestDetails {
+    pub(crate) query: Option<String>,
+    /// milliseconds
+    pub(crate) duration: u64,
+    /// milliseconds until the response headers from the app arrived
+    pub(crate) upstream_duration: Option<u64>,
+    pub(crate) request_bytes: u64,
+    pub(crate) response_bytes: u64,
+    pub(crate) client_ip: Option<String>,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) referer: Option<String>,
+    pub(crate) container: Option<String>,
+    pub(crate) cold_start: bool,
+    pub(crate) cache_status: Option<String>,
 }
 
 impl Log {
@@ -70,6 +156,28 @@ impl Log {
             path: None,
             status: None,
             message: Some(value.message),
+            request_id: None,
+            request: None,
+        }
+    }
+
+    pub(crate) fn from_build_output(
+        time: i64,
+        deployment: &NanoId,
+        output: &str,
+        error: bool,
+    ) -> Self {
+        Self {
+            level: if error { Level::ERROR } else { Level::INFO },
+            time,
+            deployment: deployment.to_string(),
+            host: None,
+            method: None,
+            path: None,
+            status: None,
+            message: Some(output.to_owned()),
+            request_id: None,
+            request: None,
         }
     }
 }
@@ -85,6 +193,20 @@ impl From<RequestLog> for Log {
             path: Some(value.path),
             status: Some(value.status),
             message: None,
+            request_id: Some(value.request_id),
+            request: Some(RequestDetails {
+                query: value.query,
+                duration: value.duration,
+                upstream_duration: value.upstream_duration,
+                request_bytes: value.request_bytes,
+                response_bytes: value.response_bytes,
+                client_ip: value.client_ip.map(|ip| ip.to_string()),
+                user_agent: value.user_agent,
+                referer: value.referer,
+                container: value.container,
+                cold_start: value.cold_start,
+                cache_status: value.cache_status,
+            }),
         }
     }
 }
@@ -106,6 +228,8 @@ impl From<BuildLog> for Log {
             path: None,
             status: None,
             message: Some(value.content),
+            request_id: None,
+            request: None,
         }
     }
 }
@@ -131,6 +255,10 @@ impl RequestLogger {
         let (sender, receiver) = mpsc::channel::<RequestLog>();
 
         let join_handle = thread::spawn(move || {
+            if let Err(error) = migrate_legacy_files(&get_log_dir()) {
+                tracing::error!("failed to migrate request logs: {error}");
+            }
+
             let file_path = get_log_dir().join(LOG_FILE_PREFIX);
             let mut log = FileRotate::new(
                 file_path,
@@ -141,7 +269,7 @@ impl RequestLogger {
             );
 
             for event in receiver {
-                let encoded: Vec<u8> = bincode::serialize(&event).unwrap();
+                let encoded = encode_record(&event);
                 log.write_all(encoded.as_slice());
             }
         });
@@ -159,24 +287,131 @@ impl RequestLogger {
     }
 }
 
+fn encode_record(event: &RequestLog) -> Vec<u8> {
+    let payload = bincode::serialize(event).unwrap();
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend_from_slice(&RECORD_MAGIC);
+    record.push(RECORD_VERSION);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    record
+}
+
+fn decode_payload(version: u8, payload: &[u8]) -> Option<RequestLog> {
+    match version {
+        1 => bincode::deserialize(payload).ok(),
+        _ => None,
+    }
+}
+
+enum Record<'a> {
+    Valid { version: u8, payload: &'a [u8] },
+    Corrupt,
+    End,
+}
+
+fn read_record(data: &[u8]) -> Record<'_> {
+    let Some(header) = data.get(..RECORD_HEADER_LEN) else {
+        return Record::End;
+    };
+    if header[..2] != RECORD_MAGIC {
+        return Record::Corrupt;
+    }
+    let version = header[2];
+    let len = u32::from_le_bytes(header[3..7].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[7..11].try_into().unwrap());
+    let payload = data
+        .get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)
+        .filter(|payload| len <= MAX_RECORD_LEN && crc32fast::hash(payload) == checksum);
+    match payload {
+        Some(payload) => Record::Valid { version, payload },
+        None => Record::Corrupt,
+    }
+}
+
+/// Iterates over the records of a file. Corrupt records are skipped by looking for the next
+/// magic, and records with a valid checksum but an unknown version are skipped entirely
 struct EventIter {
-    file: File,
+    data: Vec<u8>,
+    position: usize,
 }
 
 impl EventIter {
     fn new(path: &Path) -> io::Result<Self> {
-        Ok(Self {
-            file: File::open(path)?,
-        })
+        Ok(Self::from_bytes(fs::read(path)?))
+    }
+
+    fn from_bytes(data: Vec<u8>) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn skip_to_next_magic(&mut self) {
+        let rest = &self.data[self.position + 1..];
+        self.position = match rest.windows(2).position(|window| window == RECORD_MAGIC) {
+            Some(offset) => self.position + 1 + offset,
+            None => self.data.len(),
+        };
     }
 }
 
 impl Iterator for EventIter {
-    type Item = Log;
+    type Item = RequestLog;
     fn next(&mut self) -> Option<Self::Item> {
-        let event: RequestLog = bincode::deserialize_from(&self.file).ok()?;
-        Some(event.into())
+        loop {
+            match read_record(&self.data[self.position..]) {
+                Record::End => return None,
+                Record::Corrupt => self.skip_to_next_magic(),
+                Record::Valid { version, payload } => {
+                    self.position += RECORD_HEADER_LEN + payload.len();
+                    if let Some(event) = decode_payload(version, payload) {
+                        return Some(event);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// a file is considered framed if a valid record shows up anywhere in it, so a corrupt first
+/// frame doesn't make it look like a legacy file
+fn has_valid_record(data: &[u8]) -> bool {
+    data.windows(2)
+        .enumerate()
+        .filter(|(_, window)| *window == RECORD_MAGIC)
+        .any(|(position, _)| matches!(read_record(&data[position..]), Record::Valid { .. }))
+}
+
+/// Rewrites the files written before records were framed. Files already in the current format
+/// are left untouched, so this only does something the first time it runs. A file is only
+/// replaced if all of its bytes decode as legacy records, so no record is ever dropped
+fn migrate_legacy_files(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_log_file = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(LOG_FILE_PREFIX));
+        if !is_log_file {
+            continue;
+        }
+        let data = fs::read(&path)?;
+        if data.is_empty() || has_valid_record(&data) {
+            continue;
+        }
+
+        let mut reader = data.as_slice();
+        let mut migrated = tempfile::NamedTempFile::new_in(dir)?;
+        while let Ok(event) = bincode::deserialize_from::<_, LegacyRequestLog>(&mut reader) {
+            migrated.write_all(&encode_record(&event.into()))?;
+        }
+        if reader.is_empty() {
+            migrated.persist(&path)?;
+        } else {
+            tracing::warn!("leaving {path:?} untouched, it is not a valid legacy log file");
+        }
     }
+    Ok(())
 }
 
 pub(crate) fn read_request_event_logs() -> io::Result<impl Iterator<Item = Log>> {
@@ -184,6 +419,10 @@ pub(crate) fn read_request_event_logs() -> io::Result<impl Iterator<Item = Log>>
 
     let mut paths: Vec<_> = fs::read_dir(get_log_dir())?
         .filter_map(|entry| Some(entry.ok()?))
+        .filter(|entry| {
+            let name = entry.file_name();
+            name.to_string_lossy().starts_with(LOG_FILE_PREFIX)
+        })
         .collect();
 
     paths.sort_by_key(|path| path.file_name());
@@ -201,7 +440,8 @@ pub(crate) fn read_request_event_logs() -> io::Result<impl Iterator<Item = Log>>
         .into_iter()
         .filter_map(|path| EventIter::new(&path.path()).ok())
         .take(2)
-        .flatten();
+        .flatten()
+        .map(|event| event.into());
     Ok(events)
 }
 
@@ -247,3 +487,108 @@ pub(crate) fn read_request_event_logs() -> io::Result<impl Iterator<Item = Log>>
 //         assert_eq!(target, decoded);
 //     }
 // }
+
+#[cfg(test)]
+mod logging_tests {
+    use serde::Serialize;
+
+    use crate::utils::now;
+
+    use super::{
+        encode_record, migrate_legacy_files, EventIter, LegacyRequestLog, Level, RequestLog,
+        RECORD_HEADER_LEN, RECORD_MAGIC,
+    };
+
+    fn request_log(path: &str) -> RequestLog {
+        LegacyRequestLog {
+            time: now(),
+            level: Level::INFO,
+            deployment: "deployment".to_owned().into(),
+            host: "app.example.com".to_owned(),
+            method: "GET".to_owned(),
+            path: path.to_owned(),
+            status: 200,
+        }
+        .into()
+    }
+
+    fn read_paths(data: Vec<u8>) -> Vec<String> {
+        EventIter::from_bytes(data).map(|log| log.path).collect()
+    }
+
+    #[test]
+    fn test_corrupt_records_are_skipped() {
+        let mut data = encode_record(&request_log("/first"));
+        let mut corrupt = encode_record(&request_log("/second"));
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        data.extend(corrupt);
+        data.extend(b"garbage");
+        data.extend(RECORD_MAGIC);
+        data.extend(encode_record(&request_log("/third")));
+
+        assert_eq!(read_paths(data), vec!["/first", "/third"]);
+    }
+
+    #[test]
+    fn test_unknown_versions_are_skipped() {
+        let mut data = encode_record(&request_log("/future"));
+        data[2] = u8::MAX;
+        data.extend(encode_record(&request_log("/current")));
+
+        assert_eq!(read_paths(data), vec!["/current"]);
+    }
+
+    #[test]
+    fn test_legacy_files_are_migrated() {
+        #[derive(Serialize)]
+        struct Legacy(i64, Level, String, String, String, String, u16);
+        let legacy: Vec<u8> = ["/a", "/b"]
+            .into_iter()
+            .flat_map(|path| {
+                let log = Legacy(
+                    now(),
+                    Level::ERROR,
+                    "deployment".to_owned(),
+                    "app.example.com".to_owned(),
+                    "POST".to_owned(),
+                    path.to_owned(),
+                    500,
+                );
+                bincode::serialize(&log).unwrap()
+            })
+            .collect();
+        let current = encode_record(&request_log("/c"));
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("log.20241023T072726"), &legacy).unwrap();
+        std::fs::write(dir.path().join("log"), &current).unwrap();
+        migrate_legacy_files(dir.path()).unwrap();
+
+        let migrated = std::fs::read(dir.path().join("log.20241023T072726")).unwrap();
+        assert_eq!(read_paths(migrated), vec!["/a", "/b"]);
+        let untouched = std::fs::read(dir.path().join("log")).unwrap();
+        assert_eq!(untouched, current);
+    }
+
+    #[test]
+    fn test_corrupt_first_frame_is_not_migrated() {
+        let mut data = encode_record(&request_log("/first"));
+        data[RECORD_HEADER_LEN] ^= 0xff;
+        data.extend(encode_record(&request_log("/second")));
+        data.extend(encode_record(&request_log("/third")));
+        let mut unknown = encode_record(&request_log("/unknown"));
+        unknown[0] ^= 0xff;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("log"), &data).unwrap();
+        std::fs::write(dir.path().join("log.20241023T072726"), &unknown).unwrap();
+        migrate_legacy_files(dir.path()).unwrap();
+
+        let framed = std::fs::read(dir.path().join("log")).unwrap();
+        assert_eq!(framed, data);
+        assert_eq!(read_paths(framed), vec!["/second", "/third"]);
+        let garbage = std::fs::read(dir.path().join("log.20241023T072726")).unwrap();
+        assert_eq!(garbage, unknown);
+    }
+}
@@ -8,6 +8,7 @@ use tls::CertificateStore;
 use traces::init_tracing_subscriber;
 use tracing::info;
 
+mod analytics;
 mod api;
 mod conf;
 mod container;
@@ -18,12 +19,20 @@ mod docker_bridge;
 mod env;
 mod github;
 mod hooks;
+mod ip_filter;
 mod label;
 mod listener;
+mod log_drain;
 mod logging;
+mod metrics;
+mod notifications;
 mod paths;
+mod protection;
 mod provider;
 mod proxy;
+mod rate_limit;
+mod redact;
+mod routing;
 mod sqlite_db;
 mod tls;
 mod tokens;
@@ -34,13 +43,13 @@ pub(crate) const DOCKER_PORT: u16 = 5046;
 
 #[tokio::main]
 async fn main() {
-    let _guard = init_tracing_subscriber();
+    let conf = Conf::read();
+    let (_guard, log_filter) = init_tracing_subscriber(&conf.log);
     info!("prezel is starting...");
 
-    let conf = Conf::read();
     let cloned_conf = conf.clone();
 
-    let db = Db::setup().await;
+    let db = Db::setup(&conf.secret, conf.previous_secret.as_deref()).await;
     let github = Github::new().await;
 
     provider::setup_ip_address().await.unwrap();
@@ -51,6 +60,7 @@ async fn main() {
         github.clone(),
         db.clone(),
         certificates.clone(),
+        conf.build_concurrency,
     );
     let cloned_manager = manager.clone();
 
@@ -59,7 +69,7 @@ async fn main() {
     manager.full_sync_with_github().await;
 
     let api_hostname = format!("api.{}", &conf.hostname);
-    run_api_server(manager, db, github, &api_hostname, conf.secret)
+    run_api_server(manager, db, github, &api_hostname, conf.secret, log_filter)
         .await
         .unwrap();
 }
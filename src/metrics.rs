@@ -0,0 +1,293 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    time::Duration,
+};
+
+use walkdir::WalkDir;
+
+const REQUEST_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const BUILD_BUCKETS: &[f64] = &[10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0];
+const COLD_START_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0];
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Default::default);
+
+/// Process wide metrics, recorded from the proxy and the containers and rendered by /metrics
+pub(crate) fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+#[derive(Clone, Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    /// keyed by project and status code class
+    requests: Mutex<BTreeMap<(String, String), u64>>,
+    request_duration: Mutex<BTreeMap<String, Histogram>>,
+    /// keyed by build result
+    build_duration: Mutex<BTreeMap<&'static str, Histogram>>,
+    cold_start: Mutex<Option<Histogram>>,
+    dropped_logs: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn record_request(&self, project: &str, status: u16, duration: Duration) {
+        let class = format!("{}xx", status / 100);
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((project.to_owned(), class))
+            .or_default() += 1;
+        self.request_duration
+            .lock()
+            .unwrap()
+            .entry(project.to_owned())
+            .or_insert_with(|| Histogram::new(REQUEST_BUCKETS))
+            .observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn record_build(&self, success: bool, duration: Duration) {
+        let result = if success { "built" } else { "failed" };
+        self.build_duration
+            .lock()
+            .unwrap()
+            .entry(result)
+            .or_insert_with(|| Histogram::new(BUILD_BUCKETS))
+            .observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn record_cold_start(&self, duration: Duration) {
+        self.cold_start
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| Histogram::new(COLD_START_BUCKETS))
+            .observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn record_dropped_log(&self) {
+        self.dropped_logs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn write(&self, output: &mut MetricsWriter) {
+        output.header(
+            "prezel_http_requests_total",
+            "counter",
+            "Requests served by the proxy",
+        );
+        for ((project, class), count) in self.requests.lock().unwrap().iter() {
+            let labels = [("project", project.as_str()), ("status", class.as_str())];
+            output.sample("prezel_http_requests_total", &labels, *count as f64);
+        }
+
+        output.header(
+            "prezel_http_request_duration_seconds",
+            "histogram",
+            "Time until requests served by the proxy were fully answered",
+        );
+        for (project, histogram) in self.request_duration.lock().unwrap().iter() {
+            let labels = [("project", project.as_str())];
+            output.histogram("prezel_http_request_duration_seconds", &labels, histogram);
+        }
+
+        output.header(
+            "prezel_build_duration_seconds",
+            "histogram",
+            "Duration of the builds",
+        );
+        for (result, histogram) in self.build_duration.lock().unwrap().iter() {
+            let labels = [("result", *result)];
+            output.histogram("prezel_build_duration_seconds", &labels, histogram);
+        }
+
+        output.header(
+            "prezel_cold_start_duration_seconds",
+            "histogram",
+            "Time taken to start a container in stand by until it was online",
+        );
+        if let Some(histogram) = self.cold_start.lock().unwrap().as_ref() {
+            output.histogram("prezel_cold_start_duration_seconds", &[], histogram);
+        }
+
+        output.header(
+            "prezel_log_drain_dropped_total",
+            "counter",
+            "Logs that could not be delivered to a log drain",
+        );
+        let dropped = self.dropped_logs.load(Ordering::Relaxed);
+        output.sample("prezel_log_drain_dropped_total", &[], dropped as f64);
+    }
+}
+
+/// Values only known at scrape time
+pub(crate) struct Snapshot {
+    pub(crate) containers: HashMap<&'static str, usize>,
+    /// expiry of each certificate, epoch in seconds
+    pub(crate) certificates: Vec<(String, i64)>,
+    pub(crate) disk_usage: Vec<(String, u64)>,
+}
+
+/// renders the metrics in the Prometheus text exposition format
+pub(crate) fn render(snapshot: &Snapshot) -> String {
+    let mut output = MetricsWriter::default();
+    metrics().write(&mut output);
+
+    output.header(
+        "prezel_build_queue_depth",
+        "gauge",
+        "Containers waiting to be built",
+    );
+    let queued = snapshot.containers.get("queued").copied().unwrap_or(0);
+    output.sample("prezel_build_queue_depth", &[], queued as f64);
+
+    output.header(
+        "prezel_containers",
+        "gauge",
+        "Number of containers in each status",
+    );
+    let mut containers: Vec<_> = snapshot.containers.iter().collect();
+    containers.sort();
+    for (status, count) in containers {
+        output.sample("prezel_containers", &[("status", status)], *count as f64);
+    }
+
+    output.header(
+        "prezel_certificate_expiry_timestamp_seconds",
+        "gauge",
+        "Expiry time of the TLS certificates",
+    );
+    for (domain, expiry) in &snapshot.certificates {
+        let labels = [("domain", domain.as_str())];
+        output.sample(
+            "prezel_certificate_expiry_timestamp_seconds",
+            &labels,
+            *expiry as f64,
+        );
+    }
+
+    output.header(
+        "prezel_disk_usage_bytes",
+        "gauge",
+        "Size of the prezel data directories",
+    );
+    for (dir, size) in &snapshot.disk_usage {
+        let labels = [("dir", dir.as_str())];
+        output.sample("prezel_disk_usage_bytes", &labels, *size as f64);
+    }
+
+    output.0
+}
+
+/// sums the size of all the files under the path, which can also be a single file
+pub(crate) fn get_disk_usage(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok()?.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+#[derive(Default)]
+struct MetricsWriter(String);
+
+impl MetricsWriter {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.0, "# HELP {name} {help}").unwrap();
+        writeln!(self.0, "# TYPE {name} {kind}").unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|(key, value)| format!("{key}=\"{}\"", escape_label(value)))
+            .collect();
+        if labels.is_empty() {
+            writeln!(self.0, "{name} {value}").unwrap();
+        } else {
+            writeln!(self.0, "{name}{{{}}} {value}", labels.join(",")).unwrap();
+        }
+    }
+
+    fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let bucket_name = format!("{name}_bucket");
+        for (bound, count) in histogram.bounds.iter().zip(&histogram.counts) {
+            let bound = bound.to_string();
+            let labels = [labels, &[("le", bound.as_str())]].concat();
+            self.sample(&bucket_name, &labels, *count as f64);
+        }
+        let labels_inf = [labels, &[("le", "+Inf")]].concat();
+        self.sample(&bucket_name, &labels_inf, histogram.count as f64);
+        self.sample(&format!("{name}_sum"), labels, histogram.sum);
+        self.sample(&format!("{name}_count"), labels, histogram.count as f64);
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use std::time::Duration;
+
+    use super::{Metrics, MetricsWriter};
+
+    #[test]
+    fn test_exposition_format() {
+        let metrics = Metrics::default();
+        metrics.record_request("app", 200, Duration::from_millis(20));
+        metrics.record_request("app", 503, Duration::from_secs(20));
+
+        let mut output = MetricsWriter::default();
+        metrics.write(&mut output);
+        let output = output.0;
+
+        assert!(output.contains("prezel_http_requests_total{project=\"app\",status=\"2xx\"} 1\n"));
+        assert!(output.contains("prezel_http_requests_total{project=\"app\",status=\"5xx\"} 1\n"));
+        assert!(output.contains(
+            "prezel_http_request_duration_seconds_bucket{project=\"app\",le=\"0.025\"} 1\n"
+        ));
+        assert!(output.contains(
+            "prezel_http_request_duration_seconds_bucket{project=\"app\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(output.contains("prezel_http_request_duration_seconds_count{project=\"app\"} 2\n"));
+    }
+}
@@ -0,0 +1,441 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::Policy,
+};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::time::sleep;
+use tracing::warn;
+use url::{Host, Url};
+use utoipa::ToSchema;
+
+use crate::{
+    conf::Conf,
+    db::{nano_id::NanoId, Db, DeploymentWithProject},
+    utils::now,
+};
+
+const MAX_ATTEMPTS: i64 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// delivery attempts older than this are removed from the db, in milliseconds
+const DELIVERY_RETENTION: i64 = 30 * 24 * 60 * 60 * 1000;
+const SIGNATURE_HEADER: &str = "X-Prezel-Signature";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum NotificationEvent {
+    BuildStarted,
+    BuildFailed,
+    DeploymentReady,
+    PromotedToProd,
+    Rollback,
+    ContainerCrashed,
+}
+
+impl NotificationEvent {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::BuildStarted => "build_started",
+            Self::BuildFailed => "build_failed",
+            Self::DeploymentReady => "deployment_ready",
+            Self::PromotedToProd => "promoted_to_prod",
+            Self::Rollback => "rollback",
+            Self::ContainerCrashed => "container_crashed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum NotificationChannel {
+    /// generic JSON payload, signed with HMAC-SHA256 if a secret is set
+    Webhook {
+        url: String,
+        secret: Option<String>,
+        /// all events if missing
+        events: Option<Vec<NotificationEvent>>,
+    },
+    /// Slack incoming webhook
+    Slack {
+        url: String,
+        events: Option<Vec<NotificationEvent>>,
+    },
+    /// Discord webhook
+    Discord {
+        url: String,
+        events: Option<Vec<NotificationEvent>>,
+    },
+}
+
+impl NotificationChannel {
+    /// the instance itself and the cloud metadata endpoints are not valid destinations
+    pub(crate) fn is_valid(&self) -> bool {
+        Url::parse(self.url()).is_ok_and(|url| {
+            (url.scheme() == "http" || url.scheme() == "https")
+                && url.host().is_some_and(|host| is_public_host(&host))
+        })
+    }
+
+    fn url(&self) -> &str {
+        match self {
+            Self::Webhook { url, .. } | Self::Slack { url, .. } | Self::Discord { url, .. } => url,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Webhook { .. } => "webhook",
+            Self::Slack { .. } => "slack",
+            Self::Discord { .. } => "discord",
+        }
+    }
+
+    fn is_subscribed(&self, event: NotificationEvent) -> bool {
+        let events = match self {
+            Self::Webhook { events, .. }
+            | Self::Slack { events, .. }
+            | Self::Discord { events, .. } => events,
+        };
+        events
+            .as_ref()
+            .map_or(true, |events| events.contains(&event))
+    }
+}
+
+fn is_public_host(host: &Host<&str>) -> bool {
+    match host {
+        Host::Domain(domain) => {
+            let domain = domain.trim_end_matches('.').to_lowercase();
+            domain != "localhost" && !domain.ends_with(".localhost")
+        }
+        Host::Ipv4(ip) => is_public_ip(IpAddr::V4(*ip)),
+        Host::Ipv6(ip) => is_public_ip(IpAddr::V6(*ip)),
+    }
+}
+
+/// Private ranges are allowed on purpose: only admins set up channels, and a self-hosted server
+/// often notifies services in its own network
+fn is_public_ip(ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        ip => ip,
+    };
+    match ip {
+        IpAddr::V4(ip) => !(ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()),
+        IpAddr::V6(ip) => {
+            let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+            !(ip.is_loopback() || link_local || ip.is_unspecified())
+        }
+    }
+}
+
+/// Skips the addresses `is_valid` would reject, as it can only check the ones written in the url
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(
+                    anyhow!("{} does not resolve to a public address", name.as_str()).into(),
+                );
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// redirects and proxies would reach hosts that were never checked
+fn get_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(Policy::none())
+        .no_proxy()
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()
+        .unwrap()
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub(crate) struct NotificationDelivery {
+    pub(crate) id: i64,
+    pub(crate) event: String,
+    pub(crate) deployment: String,
+    pub(crate) channel: String,
+    pub(crate) url: String,
+    pub(crate) attempt: i64,
+    /// epoch in milliseconds
+    pub(crate) timestamp: i64,
+    /// http status of the response, if any was received
+    pub(crate) status: Option<i64>,
+    /// missing if the notification was delivered
+    pub(crate) error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct Payload {
+    id: String,
+    event: NotificationEvent,
+    project: String,
+    deployment: String,
+    branch: String,
+    sha: String,
+    url: String,
+    /// epoch in milliseconds
+    timestamp: i64,
+    /// only for container_crashed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i64>,
+}
+
+impl Payload {
+    fn get_message(&self) -> String {
+        let Self {
+            project,
+            branch,
+            url,
+            ..
+        } = self;
+        let sha: String = self.sha.chars().take(7).collect();
+        match self.event {
+            NotificationEvent::BuildStarted => {
+                format!("{project}: build started for {branch} ({sha})")
+            }
+            NotificationEvent::BuildFailed => {
+                format!("{project}: build failed for {branch} ({sha})")
+            }
+            NotificationEvent::DeploymentReady => {
+                format!("{project}: deployment for {branch} ({sha}) is ready at {url}")
+            }
+            NotificationEvent::PromotedToProd => {
+                format!("{project}: {branch} ({sha}) was promoted to production")
+            }
+            NotificationEvent::Rollback => {
+                format!("{project}: production was rolled back to {branch} ({sha})")
+            }
+            NotificationEvent::ContainerCrashed => {
+                let code = self
+                    .exit_code
+                    .map_or("unknown".to_owned(), |code| code.to_string());
+                format!("{project}: container for {branch} ({sha}) exited unexpectedly with code {code}")
+            }
+        }
+    }
+}
+
+/// Sends the event to the channels of the project in the background, recording every attempt
+pub(crate) fn notify(
+    db: &Db,
+    event: NotificationEvent,
+    deployment: &NanoId,
+    exit_code: Option<i64>,
+) {
+    let db = db.clone();
+    let deployment = deployment.clone();
+    tokio::spawn(async move {
+        let Some(info) = db.get_deployment_with_project(&deployment).await else {
+            return;
+        };
+        let channels: Vec<_> = info
+            .project
+            .notification_channels
+            .iter()
+            .filter(|channel| channel.is_subscribed(event))
+            .cloned()
+            .collect();
+        if channels.is_empty() {
+            return;
+        }
+        let Conf { hostname, .. } = Conf::read_async().await;
+        let payload = get_payload(&info, event, &hostname, exit_code);
+        let client = get_client();
+        for channel in channels {
+            deliver(&db, &client, &info.project.id, &channel, &payload).await;
+        }
+        db.delete_notification_deliveries_before(now() - DELIVERY_RETENTION)
+            .await;
+    });
+}
+
+fn get_payload(
+    info: &DeploymentWithProject,
+    event: NotificationEvent,
+    hostname: &str,
+    exit_code: Option<i64>,
+) -> Payload {
+    let url = match event {
+        NotificationEvent::PromotedToProd | NotificationEvent::Rollback => {
+            info.get_prod_base_url(hostname)
+        }
+        _ => info.get_app_base_url(hostname),
+    };
+    Payload {
+        id: uuid::Uuid::new_v4().to_string(),
+        event,
+        project: info.project.name.clone(),
+        deployment: info.deployment.id.to_string(),
+        branch: info.deployment.branch.clone(),
+        sha: info.deployment.sha.clone(),
+        url,
+        timestamp: now(),
+        exit_code,
+    }
+}
+
+async fn deliver(
+    db: &Db,
+    client: &reqwest::Client,
+    project: &NanoId,
+    channel: &NotificationChannel,
+    payload: &Payload,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let (status, result) = send(client, channel, payload).await;
+        let error = result.err().map(|error| error.to_string());
+        let delivery = NotificationDelivery {
+            id: 0,
+            event: payload.event.as_str().to_owned(),
+            deployment: payload.deployment.clone(),
+            channel: channel.kind().to_owned(),
+            url: channel.url().to_owned(),
+            attempt,
+            timestamp: now(),
+            status,
+            error: error.clone(),
+        };
+        db.insert_notification_delivery(project, &delivery).await;
+        match error {
+            None => return,
+            Some(error) => {
+                warn!(
+                    "failed to deliver {} to {}: {error}",
+                    delivery.event, delivery.url
+                );
+                if attempt < MAX_ATTEMPTS {
+                    sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        }
+    }
+}
+
+async fn send(
+    client: &reqwest::Client,
+    channel: &NotificationChannel,
+    payload: &Payload,
+) -> (Option<i64>, anyhow::Result<()>) {
+    let request = match channel {
+        NotificationChannel::Webhook { url, secret, .. } => {
+            let body = serde_json::to_vec(payload).unwrap();
+            let request = client
+                .post(url)
+                .header("Content-Type", "application/json")
+                .header("X-Prezel-Event", payload.event.as_str())
+                .header("X-Prezel-Delivery", &payload.id);
+            let request = match secret {
+                Some(secret) => request.header(SIGNATURE_HEADER, sign(secret, &body)),
+                None => request,
+            };
+            request.body(body)
+        }
+        NotificationChannel::Slack { url, .. } => client
+            .post(url)
+            .json(&json!({ "text": payload.get_message() })),
+        NotificationChannel::Discord { url, .. } => client
+            .post(url)
+            .json(&json!({ "content": payload.get_message() })),
+    };
+    match request.timeout(SEND_TIMEOUT).send().await {
+        Ok(response) => {
+            let status = response.status();
+            let result = if status.is_success() {
+                Ok(())
+            } else {
+                Err(anyhow!("got {status}"))
+            };
+            (Some(status.as_u16() as i64), result)
+        }
+        Err(error) => (None, Err(error.into())),
+    }
+}
+
+/// sha256=<hex encoded HMAC-SHA256 of the body>
+fn sign(secret: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, body);
+    let hex: String = tag
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("sha256={hex}")
+}
+
+#[cfg(test)]
+mod notifications_tests {
+    use reqwest::dns::Resolve;
+
+    use super::{sign, NotificationChannel, NotificationEvent, PublicResolver};
+
+    #[test]
+    fn test_signature() {
+        // from RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_event_filter() {
+        let channel: NotificationChannel = serde_json::from_str(
+            r#"{"type": "slack", "url": "https://hooks.slack.com/x", "events": ["build_failed"]}"#,
+        )
+        .unwrap();
+        assert!(channel.is_valid());
+        assert!(channel.is_subscribed(NotificationEvent::BuildFailed));
+        assert!(!channel.is_subscribed(NotificationEvent::BuildStarted));
+    }
+
+    #[test]
+    fn test_internal_urls_are_invalid() {
+        let is_valid = |url: &str| {
+            let channel: NotificationChannel =
+                serde_json::from_value(serde_json::json!({ "type": "webhook", "url": url }))
+                    .unwrap();
+            channel.is_valid()
+        };
+        assert!(is_valid("https://example.com/hook"));
+        // private ranges are allowed, only the instance itself and link-local are rejected
+        assert!(is_valid("http://10.0.0.5:8080/hook"));
+        assert!(!is_valid("http://localhost:5045/api"));
+        assert!(!is_valid("http://127.0.0.1/hook"));
+        assert!(!is_valid("http://169.254.169.254/latest/meta-data"));
+        assert!(!is_valid("http://[::1]/hook"));
+        assert!(!is_valid("http://[fe80::1]/hook"));
+        assert!(!is_valid("http://[::ffff:127.0.0.1]/hook"));
+        assert!(!is_valid("ftp://example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_internal_names_do_not_resolve() {
+        let resolve = |name: &str| PublicResolver.resolve(name.parse().unwrap());
+        assert!(resolve("localhost").await.is_err());
+        assert!(resolve("127.0.0.1").await.is_err());
+    }
+}
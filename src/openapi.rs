@@ -2,6 +2,7 @@ use std::fs;
 
 use api::server::get_open_api;
 
+mod analytics;
 mod api;
 mod conf;
 mod container;
@@ -11,14 +12,22 @@ mod docker;
 mod env;
 mod github;
 mod hooks;
+mod ip_filter;
 mod label;
 mod listener;
+mod log_drain;
 mod logging;
+mod metrics;
+mod notifications;
 mod paths;
+mod protection;
 mod provider;
+mod rate_limit;
+mod routing;
 mod sqlite_db;
 mod tls;
 mod tokens;
+mod traces;
 mod utils;
 
 fn main() {
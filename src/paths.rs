@@ -104,6 +104,17 @@ pub(crate) fn get_libsql_branch_dir(deployment: &str) -> PathBuf {
         .create_if_missing()
 }
 
+/// directories and files whose size is reported in the metrics
+pub(crate) fn get_disk_usage_paths() -> Vec<(&'static str, PathBuf)> {
+    vec![
+        ("apps", get_apps_dir()),
+        ("deployments", get_deployments_dir()),
+        ("log", get_log_dir()),
+        ("certs", get_certs_dir()),
+        ("app.db", get_instance_db_path()),
+    ]
+}
+
 fn iter_dir(path: &Path) -> impl Iterator<Item = PathBuf> {
     let paths = read_dir(path)
         .map(|paths| paths.collect::<Vec<_>>())
@@ -0,0 +1,227 @@
+use std::{collections::HashSet, num::NonZeroU32, sync::Mutex};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use ring::{
+    digest, pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use utoipa::ToSchema;
+
+use crate::{
+    tokens::{decode_audience_token, generate_token},
+    utils::now_in_seconds,
+};
+
+/// query param carrying the share token in share links
+pub(crate) const SHARE_QUERY_PARAM: &str = "prezel-share";
+/// name of the host-only cookie the share token is exchanged for
+pub(crate) const SHARE_COOKIE: &str = "prezel-share";
+/// share links can't be valid for longer than 30 days
+pub(crate) const MAX_SHARE_SECONDS: i64 = 30 * 24 * 60 * 60;
+
+/// audience of share tokens, API tokens have none so they can't be used for sharing and the
+/// other way around
+const SHARE_AUDIENCE: &str = "prezel-share";
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+/// password hashes computed at the same time, so unauthenticated clients can't take the cpu over
+const MAX_CONCURRENT_VERIFICATIONS: usize = 2;
+const MAX_VERIFIED_CREDENTIALS: usize = 1024;
+const SALT_LEN: usize = 16;
+static PBKDF2_ALGORITHM: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub(crate) struct BasicAuthCredentials {
+    pub(crate) username: String,
+    pub(crate) password: String,
+}
+
+impl BasicAuthCredentials {
+    /// the password is stored as base64(salt):base64(pbkdf2 hash)
+    pub(crate) fn hash_password(&self) -> String {
+        let mut salt = [0u8; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).unwrap();
+        let mut hash = [0u8; ring::digest::SHA256_OUTPUT_LEN];
+        pbkdf2::derive(
+            PBKDF2_ALGORITHM,
+            iterations(),
+            &salt,
+            self.password.as_bytes(),
+            &mut hash,
+        );
+        format!("{}:{}", STANDARD.encode(salt), STANDARD.encode(hash))
+    }
+}
+
+/// Basic auth protection for the private deployments of a project
+#[derive(Clone)]
+pub(crate) struct BasicAuth {
+    pub(crate) username: String,
+    pub(crate) password_hash: String,
+}
+
+impl std::fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<hidden basic auth>")
+    }
+}
+
+impl BasicAuth {
+    /// this is slow on purpose, so it should not run on the async executor
+    fn verify_password(&self, password: &str) -> bool {
+        let Some((salt, hash)) = self.password_hash.split_once(':') else {
+            return false;
+        };
+        let (Ok(salt), Ok(hash)) = (STANDARD.decode(salt), STANDARD.decode(hash)) else {
+            return false;
+        };
+        pbkdf2::verify(
+            PBKDF2_ALGORITHM,
+            iterations(),
+            &salt,
+            password.as_bytes(),
+            &hash,
+        )
+        .is_ok()
+    }
+}
+
+/// returns the username and password of an Authorization header
+fn parse_basic_auth_header(header: &str) -> Option<(String, String)> {
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_owned(), password.to_owned()))
+}
+
+/// Validates Authorization headers, hashing the password only the first time a header is seen
+pub(crate) struct BasicAuthVerifier {
+    /// sha256 of the stored hash and the header, so changing the password invalidates them
+    verified: Mutex<HashSet<Vec<u8>>>,
+    permits: Semaphore,
+}
+
+impl BasicAuthVerifier {
+    pub(crate) fn new() -> Self {
+        Self {
+            verified: Default::default(),
+            permits: Semaphore::new(MAX_CONCURRENT_VERIFICATIONS),
+        }
+    }
+
+    pub(crate) async fn validate_header(&self, auth: &BasicAuth, header: &str) -> bool {
+        let key = digest::digest(
+            &digest::SHA256,
+            format!("{}\n{header}", auth.password_hash).as_bytes(),
+        )
+        .as_ref()
+        .to_vec();
+        if self.verified.lock().unwrap().contains(&key) {
+            return true;
+        }
+
+        let Some((username, password)) = parse_basic_auth_header(header) else {
+            return false;
+        };
+        if username != auth.username {
+            return false;
+        }
+        let Ok(_permit) = self.permits.acquire().await else {
+            return false;
+        };
+        let auth = auth.clone();
+        let valid = tokio::task::spawn_blocking(move || auth.verify_password(&password))
+            .await
+            .unwrap_or(false);
+
+        if valid {
+            let mut verified = self.verified.lock().unwrap();
+            if verified.len() >= MAX_VERIFIED_CREDENTIALS {
+                verified.clear();
+            }
+            verified.insert(key);
+        }
+        valid
+    }
+}
+
+fn iterations() -> NonZeroU32 {
+    NonZeroU32::new(PBKDF2_ITERATIONS).unwrap()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ShareClaims {
+    host: String,
+    exp: i64,
+    aud: String,
+}
+
+/// generates a token granting access to the given host until `exp` (epoch in seconds)
+pub(crate) fn generate_share_token(host: &str, exp: i64, secret: &str) -> String {
+    let claims = ShareClaims {
+        host: host.to_owned(),
+        exp,
+        aud: SHARE_AUDIENCE.to_owned(),
+    };
+    generate_token(claims, secret)
+}
+
+/// returns the expiration of the token if it is valid for the given host
+pub(crate) fn validate_share_token(token: &str, host: &str, secret: &str) -> Option<i64> {
+    let claims: ShareClaims = decode_audience_token(token, secret, SHARE_AUDIENCE).ok()?;
+    (claims.host == host && claims.exp > now_in_seconds()).then_some(claims.exp)
+}
+
+#[cfg(test)]
+mod protection_tests {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+    use crate::{
+        tokens::{decode_auth_token, generate_token, Role, TokenClaims},
+        utils::now_in_seconds,
+    };
+
+    use super::{
+        generate_share_token, validate_share_token, BasicAuth, BasicAuthCredentials,
+        BasicAuthVerifier,
+    };
+
+    #[tokio::test]
+    async fn test_basic_auth() {
+        let credentials = BasicAuthCredentials {
+            username: "client".to_owned(),
+            password: "hunter2".to_owned(),
+        };
+        let auth = BasicAuth {
+            username: credentials.username.clone(),
+            password_hash: credentials.hash_password(),
+        };
+        let valid = format!("Basic {}", STANDARD.encode("client:hunter2"));
+        let invalid = format!("Basic {}", STANDARD.encode("client:hunter3"));
+        let verifier = BasicAuthVerifier::new();
+        assert!(verifier.validate_header(&auth, &valid).await);
+        // the second time it is served from the cache
+        assert!(verifier.validate_header(&auth, &valid).await);
+        assert!(!verifier.validate_header(&auth, &invalid).await);
+        assert!(!verifier.validate_header(&auth, "Bearer token").await);
+    }
+
+    #[test]
+    fn test_share_token_is_scoped_to_host() {
+        let exp = now_in_seconds() + 60;
+        let token = generate_share_token("app--abc.example.com", exp, "secret");
+        assert_eq!(
+            validate_share_token(&token, "app--abc.example.com", "secret"),
+            Some(exp)
+        );
+        assert!(validate_share_token(&token, "app--def.example.com", "secret").is_none());
+        assert!(validate_share_token(&token, "app--abc.example.com", "other").is_none());
+
+        // share and API tokens are not interchangeable
+        assert!(decode_auth_token(&token, "secret").is_err());
+        let api_token = generate_token(TokenClaims { role: Role::Admin }, "secret");
+        assert!(validate_share_token(&api_token, "app--abc.example.com", "secret").is_none());
+    }
+}
@@ -1,11 +1,13 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use cookie::Cookie;
 use http::{header, Response, StatusCode};
 use hyper::body::Bytes;
 use pingora::apps::http_app::ServeHttp;
-use pingora::http::ResponseHeader;
+use pingora::http::{RequestHeader, ResponseHeader};
 use pingora::listeners::TlsSettings;
 use pingora::prelude::http_proxy_service;
 use pingora::prelude::{HttpPeer, ProxyHttp, Result, Session};
@@ -15,17 +17,30 @@ use pingora::services::listening::Service;
 use pingora::tls::ssl::{NameType, SniError, SslContext, SslFiletype, SslMethod};
 use pingora::ErrorType::Custom;
 use pingora::{Error, ErrorSource};
+use tracing::{field::Empty, info_span, Span};
 use url::Url;
 
 use crate::api::API_PORT;
 use crate::conf::Conf;
 use crate::db::nano_id::NanoId;
-use crate::deployments::manager::Manager;
+use crate::db::Project;
+use crate::deployments::manager::{HostedContainer, Manager};
+use crate::ip_filter::{get_client_ip, get_forwarded_for, is_ip_allowed, Environment};
 use crate::listener::{Access, Listener};
+use crate::log_drain::LogSource;
 use crate::logging::{Level, RequestLog, RequestLogger};
+use crate::metrics::metrics;
+use crate::protection::{
+    validate_share_token, BasicAuth, BasicAuthVerifier, SHARE_COOKIE, SHARE_QUERY_PARAM,
+};
+use crate::redact::REDACTED;
+use crate::routing::RoutingRules;
 use crate::tls::{CertificateStore, TlsState};
 use crate::tokens::decode_auth_token;
-use crate::utils::now;
+use crate::traces::{get_propagation_headers, set_parent_from_headers};
+use crate::utils::{now, now_in_seconds};
+
+const REQUEST_ID_HEADER: &str = "X-Prezel-Request-Id";
 
 struct ApiListener;
 
@@ -38,11 +53,17 @@ impl Listener for ApiListener {
     fn is_public(&self) -> bool {
         true
     }
+    async fn container_id(&self) -> Option<String> {
+        None
+    }
 }
 
 struct Peer {
     listener: Box<dyn Listener>,
     deployment_id: Option<NanoId>,
+    routes: Arc<RoutingRules>,
+    project: Option<Arc<Project>>,
+    environment: Environment,
 }
 
 impl<L: Listener + 'static> From<L> for Peer {
@@ -50,6 +71,9 @@ impl<L: Listener + 'static> From<L> for Peer {
         Peer {
             listener: Box::new(value),
             deployment_id: None,
+            routes: Default::default(),
+            project: None,
+            environment: Environment::Production,
         }
     }
 }
@@ -58,6 +82,7 @@ struct ProxyApp {
     manager: Manager,
     config: Conf,
     request_logger: RequestLogger,
+    basic_auth: BasicAuthVerifier,
 }
 
 impl ProxyApp {
@@ -68,11 +93,19 @@ impl ProxyApp {
         if host == self.config.api_hostname() {
             Some(ApiListener.into())
         } else {
-            let container = self.manager.get_container_by_hostname(host).await?;
+            let HostedContainer {
+                container,
+                project,
+                environment,
+            } = self.manager.get_container_by_hostname(host).await?;
             let deployment_id = container.logging_deployment_id.clone();
+            let routes = container.settings.read().unwrap().routes.clone();
             Some(Peer {
                 listener: Box::new(container),
                 deployment_id,
+                routes,
+                project,
+                environment,
             })
         }
     }
@@ -99,19 +132,141 @@ impl ProxyApp {
             })
             .is_some()
     }
+
+    fn get_client_ip(&self, session: &Session) -> Option<IpAddr> {
+        let peer = get_peer_ip(session)?;
+        let forwarded_for = get_incoming_forwarded_for(session);
+        Some(get_client_ip(
+            peer,
+            forwarded_for.as_deref(),
+            &self.config.trusted_proxies,
+        ))
+    }
+
+    fn has_share_cookie(&self, session: &Session, host: &str) -> bool {
+        session
+            .get_header(header::COOKIE)
+            .and_then(|header| header.to_str().ok())
+            .is_some_and(|cookie_header| {
+                Cookie::split_parse(cookie_header)
+                    .filter_map(|cookie| cookie.ok())
+                    .any(|cookie| {
+                        cookie.name() == SHARE_COOKIE
+                            && validate_share_token(cookie.value(), host, &self.config.secret)
+                                .is_some()
+                    })
+            })
+    }
+
+    async fn has_basic_auth(&self, session: &Session, auth: &BasicAuth) -> bool {
+        let header = session
+            .get_header(header::AUTHORIZATION)
+            .and_then(|header| header.to_str().ok());
+        match header {
+            Some(header) => self.basic_auth.validate_header(auth, header).await,
+            None => false,
+        }
+    }
+
+    /// if the request carries a valid share token in the query, returns the
+    /// same location without it, together with the token and its expiration
+    fn get_share_redirect(&self, session: &Session, host: &str) -> Option<(String, String, i64)> {
+        let uri = &session.req_header().uri;
+        let (token, query): (Vec<_>, Vec<_>) = url::form_urlencoded::parse(uri.query()?.as_bytes())
+            .into_owned()
+            .partition(|(key, _)| key == SHARE_QUERY_PARAM);
+        let (_, token) = token.into_iter().next()?;
+        let exp = validate_share_token(&token, host, &self.config.secret)?;
+        Some((get_share_location(uri.path(), query), token, exp))
+    }
+}
+
+/// browsers take a location starting with `//` or `/\` as a different host, so the leading
+/// slashes are collapsed into one
+fn get_share_location(path: &str, query: Vec<(String, String)>) -> String {
+    let location = format!("/{}", path.trim_start_matches(['/', '\\']));
+    if query.is_empty() {
+        return location;
+    }
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(query)
+        .finish();
+    format!("{location}?{query}")
+}
+
+fn get_peer_ip(session: &Session) -> Option<IpAddr> {
+    Some(session.client_addr()?.as_inet()?.ip())
+}
+
+/// all the X-Forwarded-For headers in the incoming request, joined
+fn get_incoming_forwarded_for(session: &Session) -> Option<String> {
+    let forwarded_for = session
+        .req_header()
+        .headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    (!forwarded_for.is_empty()).then_some(forwarded_for)
+}
+
+/// builds a response answered by the proxy itself, carrying the request id
+fn build_response(code: StatusCode, ctx: &RequestCtx) -> Result<Box<ResponseHeader>> {
+    let mut resp = ResponseHeader::build(code, None)?;
+    resp.insert_header(REQUEST_ID_HEADER, ctx.request_id.as_str())?;
+    Ok(resp.into())
 }
 
-#[derive(Default)]
 struct RequestCtx {
+    request_id: String,
+    received: Instant,
     deployment: Option<NanoId>,
+    /// project name, used as a label in the metrics
+    project: Option<String>,
     socket: Option<SocketAddrV4>,
+    client_ip: Option<IpAddr>,
+    headers: Vec<(String, String)>,
+    upstream_sent: Option<Instant>,
+    upstream_duration: Option<Duration>,
+    container: Option<String>,
+    cold_start: bool,
+    cache_status: Option<String>,
+    span: Span,
 }
 
 #[async_trait]
 impl ProxyHttp for ProxyApp {
     type CTX = RequestCtx;
     fn new_ctx(&self) -> Self::CTX {
-        Default::default()
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let span = info_span!(
+            parent: None,
+            "proxy_request",
+            otel.kind = "server",
+            request_id = %request_id,
+            http.request.method = Empty,
+            url.path = Empty,
+            server.address = Empty,
+            prezel.deployment = Empty,
+            prezel.cold_start = Empty,
+            http.response.status_code = Empty,
+        );
+        RequestCtx {
+            request_id,
+            received: Instant::now(),
+            deployment: None,
+            project: None,
+            socket: None,
+            client_ip: None,
+            headers: vec![],
+            upstream_sent: None,
+            upstream_duration: None,
+            container: None,
+            cold_start: false,
+            cache_status: None,
+            span,
+        }
     }
 
     async fn upstream_peer(
@@ -132,11 +287,116 @@ impl ProxyHttp for ProxyApp {
         let Peer {
             listener,
             deployment_id,
+            routes,
+            project,
+            environment,
         } = self.get_listener(session).await?;
         ctx.deployment = deployment_id;
+        record_request_span(session, ctx);
+        ctx.project = project.as_ref().map(|project| project.name.clone());
+        ctx.client_ip = self.get_client_ip(session);
+
+        if let Some(project) = project
+            .as_ref()
+            .filter(|project| !project.ip_rules.is_empty())
+        {
+            let allowed = ctx
+                .client_ip
+                .is_some_and(|ip| is_ip_allowed(&project.ip_rules, environment, ip));
+            if !allowed {
+                // the request still gets logged with the deployment id set above
+                let mut resp = build_response(StatusCode::FORBIDDEN, ctx)?;
+                resp.insert_header(header::CONTENT_LENGTH, "0")?;
+                session.set_keepalive(None); // TODO: review this?
+                session.write_response_header(resp, true).await?;
+                return Ok(true);
+            }
+        }
+
+        // limits are enforced before anything else to keep floods from triggering container starts
+        if let Some(project) = &project {
+            let limiter = &self.manager.rate_limiter;
+            let deployment = ctx.deployment.as_ref();
+            if let Err(wait) =
+                limiter.check(&project.id, &project.rate_limits, deployment, ctx.client_ip)
+            {
+                let retry_after = (wait.as_secs_f64().ceil() as u64).max(1);
+                let code = StatusCode::TOO_MANY_REQUESTS;
+                let mut resp = build_response(code, ctx)?;
+                resp.insert_header(header::RETRY_AFTER, retry_after.to_string())?;
+                resp.insert_header(header::CONTENT_LENGTH, "0")?;
+                session.set_keepalive(None); // TODO: review this?
+                session.write_response_header(resp, true).await?;
+                return Ok(true);
+            }
+        }
 
-        // let listener = self.get_listener(session).await?.listener;
-        if listener.is_public() || self.is_authenticated(session) {
+        let host = session
+            .get_header(header::HOST)
+            .and_then(|header| header.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+
+        let public = listener.is_public();
+        if !public {
+            // share links are exchanged for a host-only cookie so the token doesn't stay in the URL
+            if let Some((location, token, exp)) = self.get_share_redirect(session, &host) {
+                let max_age = exp - now_in_seconds();
+                let cookie = format!(
+                    "{SHARE_COOKIE}={token}; Path=/; Max-Age={max_age}; Secure; HttpOnly; SameSite=Lax"
+                );
+                let mut resp = build_response(StatusCode::FOUND, ctx)?;
+                resp.insert_header(header::LOCATION, location)?;
+                resp.insert_header(header::SET_COOKIE, cookie)?;
+                session.set_keepalive(None); // TODO: review this?
+                session.write_response_header(resp, true).await?;
+                return Ok(true);
+            }
+        }
+
+        let basic_auth = project
+            .as_ref()
+            .and_then(|project| project.basic_auth.as_ref());
+        let basic_authenticated = match basic_auth {
+            Some(auth) if !public => self.has_basic_auth(session, auth).await,
+            _ => false,
+        };
+        if basic_authenticated {
+            // the credentials are meant for prezel, not for the app
+            session
+                .req_header_mut()
+                .remove_header(&header::AUTHORIZATION);
+        }
+
+        if public
+            || basic_authenticated
+            || self.is_authenticated(session)
+            || self.has_share_cookie(session, &host)
+        {
+            let uri = &session.req_header().uri;
+            let path = uri.path().to_owned();
+            let query = uri.query().map(str::to_owned);
+            ctx.headers = routes.headers(&path);
+
+            // redirects are answered before accessing the container so they never trigger a cold start
+            if let Some((code, location)) = routes.redirect(&path, query.as_deref()) {
+                let mut resp = build_response(code, ctx)?;
+                resp.insert_header(header::LOCATION, location)?;
+                for (key, value) in ctx.headers.drain(..) {
+                    resp.insert_header(key, value)?;
+                }
+                session.set_keepalive(None); // TODO: review this?
+                session.write_response_header(resp, true).await?;
+                return Ok(true);
+            }
+            if let Some(destination) = routes.rewrite(&path, query.as_deref()) {
+                let uri = destination.parse().map_err(|error| {
+                    Error::because(Custom("Invalid rewrite destination"), destination, error)
+                })?;
+                session.req_header_mut().set_uri(uri);
+            }
+
+            let was_running = listener.container_id().await.is_some();
             let access = listener.access().await.map_err(|error| {
                 dbg!(&error);
                 Error::create(
@@ -149,11 +409,14 @@ impl ProxyHttp for ProxyApp {
             match access {
                 Access::Socket(socket) => {
                     ctx.socket = Some(socket);
+                    ctx.container = listener.container_id().await;
+                    ctx.cold_start = !was_running && ctx.container.is_some();
                     Ok(false)
                 }
                 Access::Loading => {
+                    ctx.cold_start = true;
                     let code = StatusCode::OK;
-                    let mut resp: Box<_> = ResponseHeader::build(code, None)?.into();
+                    let mut resp = build_response(code, ctx)?;
                     resp.insert_header("Prezel-Loading", "true")?;
                     session.set_keepalive(None); // TODO: review this?
                     session.write_response_header(resp, false).await?;
@@ -168,8 +431,15 @@ impl ProxyHttp for ProxyApp {
                     Ok(true)
                 }
             }
+        } else if let Some(project) = project.filter(|project| project.basic_auth.is_some()) {
+            let realm = project.name.replace('"', "");
+            let mut resp = build_response(StatusCode::UNAUTHORIZED, ctx)?;
+            resp.insert_header(header::WWW_AUTHENTICATE, format!("Basic realm=\"{realm}\""))?;
+            resp.insert_header(header::CONTENT_LENGTH, "0")?;
+            session.set_keepalive(None); // TODO: review this?
+            session.write_response_header(resp, true).await?;
+            Ok(true)
         } else {
-            let host = session.get_header(header::HOST).unwrap().to_str().unwrap();
             let path = session.req_header().uri.path();
             let callback = Url::parse(&format!("https://{host}{path}")).unwrap();
 
@@ -180,7 +450,7 @@ impl ProxyHttp for ProxyApp {
                 .append_pair("callback", callback.as_str());
 
             let code = StatusCode::FOUND;
-            let mut resp: Box<_> = ResponseHeader::build(code, None)?.into();
+            let mut resp = build_response(code, ctx)?;
             resp.insert_header(header::LOCATION, redirect.as_str())?;
             session.set_keepalive(None); // TODO: review this?
             session.write_response_header(resp, true).await?;
@@ -188,16 +458,59 @@ impl ProxyHttp for ProxyApp {
         }
     }
 
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if let Some(peer) = get_peer_ip(session) {
+            let incoming = get_incoming_forwarded_for(session);
+            let trusted_proxies = &self.config.trusted_proxies;
+            let forwarded_for = get_forwarded_for(peer, incoming.as_deref(), trusted_proxies);
+            upstream_request.insert_header("X-Forwarded-For", forwarded_for)?;
+        }
+        if let Some(client_ip) = ctx.client_ip {
+            upstream_request.insert_header("X-Real-IP", client_ip.to_string())?;
+        }
+        if let Some(host) = session.get_header(header::HOST).cloned() {
+            upstream_request.insert_header("X-Forwarded-Host", host)?;
+        }
+        // plain HTTP requests never reach this point, they are redirected by HttpHandler
+        upstream_request.insert_header("X-Forwarded-Proto", "https")?;
+        upstream_request.insert_header(REQUEST_ID_HEADER, ctx.request_id.as_str())?;
+        for (name, value) in get_propagation_headers(&ctx.span) {
+            upstream_request.insert_header(name, value)?;
+        }
+        ctx.upstream_sent = Some(Instant::now());
+        Ok(())
+    }
+
     // TODO: try removing this and see if everything still works, including loading favicons in the console
     async fn response_filter(
         &self,
         session: &mut Session,
         upstream_response: &mut ResponseHeader,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<()>
     where
         Self::CTX: Send + Sync,
     {
+        ctx.upstream_duration = ctx.upstream_sent.map(|sent| sent.elapsed());
+        ctx.cache_status = ["Cache-Status", "X-Cache"]
+            .into_iter()
+            .find_map(|name| upstream_response.headers.get(name))
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+
+        for (key, value) in ctx.headers.drain(..) {
+            upstream_response.insert_header(key, value)?;
+        }
+        upstream_response.insert_header(REQUEST_ID_HEADER, ctx.request_id.as_str())?;
+
         let origin = session.get_header(header::ORIGIN);
         let console = origin.is_some_and(|header| header.to_str().unwrap() == self.config.provider);
 
@@ -218,16 +531,54 @@ impl ProxyHttp for ProxyApp {
         _e: Option<&pingora::Error>,
         ctx: &mut Self::CTX,
     ) {
-        logging(session, ctx, &self.request_logger);
+        if let Some(response) = session.response_written() {
+            let status = response.status.as_u16();
+            ctx.span.record("http.response.status_code", status);
+        }
+        ctx.span.record("prezel.cold_start", ctx.cold_start);
+        if let Some(log) = get_request_log(session, ctx) {
+            if let Some(project) = &ctx.project {
+                let duration = ctx.received.elapsed();
+                metrics().record_request(project, log.status, duration);
+            }
+            self.manager.analytics.record(&log);
+            let drain_log = log.clone().into();
+            self.manager.log_drains.send(LogSource::Request, drain_log);
+            self.request_logger.log(log);
+        }
     }
 }
 
-fn logging(session: &Session, ctx: &RequestCtx, logger: &RequestLogger) -> Option<()> {
+fn record_request_span(session: &Session, ctx: &RequestCtx) {
+    let request = session.req_header();
+    set_parent_from_headers(&ctx.span, &request.headers);
+    ctx.span
+        .record("http.request.method", request.method.as_str());
+    ctx.span.record("url.path", request.uri.path());
+    if let Some(host) = session
+        .get_header(header::HOST)
+        .and_then(|header| header.to_str().ok())
+    {
+        ctx.span.record("server.address", host);
+    }
+    if let Some(deployment) = &ctx.deployment {
+        ctx.span.record("prezel.deployment", deployment.to_string());
+    }
+}
+
+fn get_request_log(session: &Session, ctx: &RequestCtx) -> Option<RequestLog> {
     let host = session.get_header(header::HOST)?.to_str().ok()?.to_owned();
     let path = session.req_header().uri.path().to_owned();
+    let query = session.req_header().uri.query().map(redact_query);
     let method = session.req_header().method.as_str().to_owned();
-    let deployment = ctx.deployment.clone()?; // I could also add a header to the incoming request X-Prezel-Request-Id to identify the deployment
+    let deployment = ctx.deployment.clone()?;
     let response = session.response_written()?;
+    let get_header = |name: header::HeaderName| {
+        session
+            .get_header(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    };
 
     let level = if response.status.is_client_error() || response.status.is_server_error() {
         Level::ERROR
@@ -235,17 +586,29 @@ fn logging(session: &Session, ctx: &RequestCtx, logger: &RequestLogger) -> Optio
         Level::INFO
     };
 
-    logger.log(RequestLog {
+    Some(RequestLog {
         level,
         deployment,
         time: now(),
         host,
         method,
         path,
+        query,
         status: response.status.as_u16(),
-    });
-
-    Some(())
+        request_id: ctx.request_id.clone(),
+        duration: ctx.received.elapsed().as_millis() as u64,
+        upstream_duration: ctx
+            .upstream_duration
+            .map(|duration| duration.as_millis() as u64),
+        request_bytes: session.body_bytes_read() as u64,
+        response_bytes: session.body_bytes_sent() as u64,
+        client_ip: ctx.client_ip,
+        user_agent: get_header(header::USER_AGENT),
+        referer: get_header(header::REFERER),
+        container: ctx.container.clone(),
+        cold_start: ctx.cold_start,
+        cache_status: ctx.cache_status.clone(),
+    })
 }
 
 struct HttpHandler {
@@ -302,6 +665,34 @@ impl ServeHttp for HttpHandler {
     }
 }
 
+const SENSITIVE_QUERY_PARAMS: [&str; 6] =
+    ["token", "secret", "password", "key", "auth", "signature"];
+
+/// query strings end up in the request logs and the log drains, so the share token and any
+/// param that looks like a credential are replaced before storing them
+fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| {
+            let (key, _) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = url::form_urlencoded::parse(key.as_bytes())
+                .next()
+                .map(|(name, _)| name.to_lowercase())
+                .unwrap_or_default();
+            let sensitive = name == SHARE_QUERY_PARAM
+                || SENSITIVE_QUERY_PARAMS
+                    .iter()
+                    .any(|param| name.contains(param));
+            if sensitive {
+                format!("{key}={REDACTED}")
+            } else {
+                pair.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
 pub(crate) fn run_proxy(manager: Manager, config: Conf, store: CertificateStore) {
     let request_logger = RequestLogger::new();
     let mut server = Server::new(None).unwrap();
@@ -310,6 +701,7 @@ pub(crate) fn run_proxy(manager: Manager, config: Conf, store: CertificateStore)
         manager,
         config,
         request_logger,
+        basic_auth: BasicAuthVerifier::new(),
     };
     let mut https_service = http_proxy_service(&server.configuration, proxy_app);
     let certificate = store.get_default_certificate();
@@ -365,3 +757,32 @@ pub(crate) fn run_proxy(manager: Manager, config: Conf, store: CertificateStore)
 
     server.run_forever();
 }
+
+#[cfg(test)]
+mod proxy_tests {
+    use super::{get_share_location, redact_query};
+
+    #[test]
+    fn test_redact_query() {
+        assert_eq!(
+            redact_query("prezel-share=abc&page=2&access_token=xyz&api_key=k"),
+            "prezel-share=[REDACTED]&page=2&access_token=[REDACTED]&api_key=[REDACTED]"
+        );
+        assert_eq!(redact_query("q=hello+world&flag"), "q=hello+world&flag");
+    }
+
+    #[test]
+    fn test_share_location_stays_on_host() {
+        let query = vec![("page".to_owned(), "2".to_owned())];
+        assert_eq!(get_share_location("/docs/a", query), "/docs/a?page=2");
+        assert_eq!(
+            get_share_location("//evil.example", vec![]),
+            "/evil.example"
+        );
+        assert_eq!(
+            get_share_location("/\\evil.example/x", vec![]),
+            "/evil.example/x"
+        );
+        assert_eq!(get_share_location("/", vec![]), "/");
+    }
+}
@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::db::nano_id::NanoId;
+
+/// buckets are pruned once there are more than this many, mostly to bound the per-IP ones
+const MAX_BUCKETS: usize = 10_000;
+
+/// Token bucket allowing `requests` in a burst, refilled at `requests` every `seconds`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+pub(crate) struct RateLimit {
+    pub(crate) requests: u32,
+    pub(crate) seconds: u32,
+}
+
+impl RateLimit {
+    fn is_valid(&self) -> bool {
+        self.requests > 0 && self.seconds > 0
+    }
+
+    fn capacity(&self) -> f64 {
+        self.requests as f64
+    }
+
+    fn refill_rate(&self) -> f64 {
+        self.requests as f64 / self.seconds as f64
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, ToSchema)]
+pub(crate) struct RateLimits {
+    /// shared by all the deployments of the project
+    pub(crate) project: Option<RateLimit>,
+    /// applied to each deployment separately
+    pub(crate) deployment: Option<RateLimit>,
+    /// applied to each client IP separately, across the whole project
+    pub(crate) ip: Option<RateLimit>,
+}
+
+impl RateLimits {
+    pub(crate) fn is_valid(&self) -> bool {
+        [self.project, self.deployment, self.ip]
+            .iter()
+            .flatten()
+            .all(RateLimit::is_valid)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.project.is_none() && self.deployment.is_none() && self.ip.is_none()
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, ToSchema)]
+pub(crate) struct RateLimitCounters {
+    pub(crate) allowed: u64,
+    pub(crate) limited: u64,
+}
+
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+enum BucketKey {
+    Project(NanoId),
+    Deployment(NanoId),
+    Ip(NanoId, IpAddr),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// last request using the bucket, refills don't count
+    last_seen: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.capacity(),
+            updated: now,
+            last_seen: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.refill_rate()).min(limit.capacity());
+        self.updated = now;
+    }
+
+    /// time until the next token is available, or None if there is one already
+    fn wait(&self, limit: &RateLimit) -> Option<Duration> {
+        (self.tokens < 1.0)
+            .then(|| Duration::from_secs_f64((1.0 - self.tokens) / limit.refill_rate()))
+    }
+}
+
+#[derive(Debug, Default)]
+struct RateLimiterState {
+    buckets: HashMap<BucketKey, (RateLimit, Bucket)>,
+    counters: HashMap<NanoId, RateLimitCounters>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RateLimiter {
+    state: Arc<Mutex<RateLimiterState>>,
+}
+
+impl RateLimiter {
+    /// Takes a token from every bucket that applies to the request, or none of them if any is
+    /// empty. In that case, returns how long the client should wait before retrying
+    pub(crate) fn check(
+        &self,
+        project: &NanoId,
+        limits: &RateLimits,
+        deployment: Option<&NanoId>,
+        ip: Option<IpAddr>,
+    ) -> Result<(), Duration> {
+        if limits.is_empty() {
+            return Ok(());
+        }
+        let keys = [
+            limits
+                .project
+                .map(|limit| (BucketKey::Project(project.clone()), limit)),
+            limits
+                .deployment
+                .zip(deployment)
+                .map(|(limit, deployment)| (BucketKey::Deployment(deployment.clone()), limit)),
+            limits
+                .ip
+                .zip(ip)
+                .map(|(limit, ip)| (BucketKey::Ip(project.clone(), ip), limit)),
+        ];
+
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if state.buckets.len() > MAX_BUCKETS {
+            state.prune(now);
+        }
+
+        let mut wait = None;
+        for (key, limit) in keys.iter().flatten() {
+            let (current, bucket) = state
+                .buckets
+                .entry(key.clone())
+                .or_insert_with(|| (*limit, Bucket::new(limit, now)));
+            if current != limit {
+                // limits were updated, start over with a full bucket
+                *current = *limit;
+                *bucket = Bucket::new(limit, now);
+            }
+            bucket.refill(limit, now);
+            bucket.last_seen = now;
+            wait = wait.max(bucket.wait(limit));
+        }
+
+        let result = match wait {
+            Some(wait) => Err(wait),
+            None => {
+                for (key, _) in keys.iter().flatten() {
+                    if let Some((_, bucket)) = state.buckets.get_mut(key) {
+                        bucket.tokens -= 1.0;
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        let counters = state.counters.entry(project.clone()).or_default();
+        match result {
+            Ok(()) => counters.allowed += 1,
+            Err(_) => counters.limited += 1,
+        }
+        result
+    }
+
+    pub(crate) fn get_counters(&self, project: &NanoId) -> RateLimitCounters {
+        let state = self.state.lock().unwrap();
+        state.counters.get(project).copied().unwrap_or_default()
+    }
+}
+
+impl RateLimiterState {
+    /// Drops the buckets that would be full by now, as they are equivalent to missing ones. If
+    /// that is not enough, the least recently used ones go too. Pruning down to half the maximum
+    /// keeps it from running on every request when there are many clients
+    fn prune(&mut self, now: Instant) {
+        self.buckets.retain(|_, (limit, bucket)| {
+            bucket.refill(limit, now);
+            bucket.tokens < limit.capacity()
+        });
+
+        let target = MAX_BUCKETS / 2;
+        if self.buckets.len() > target {
+            let mut last_seen: Vec<Instant> = self
+                .buckets
+                .values()
+                .map(|(_, bucket)| bucket.last_seen)
+                .collect();
+            let evicted = last_seen.len() - target;
+            let (_, cutoff, _) = last_seen.select_nth_unstable(evicted);
+            let cutoff = *cutoff;
+            self.buckets
+                .retain(|_, (_, bucket)| bucket.last_seen >= cutoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod rate_limit_tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use crate::db::nano_id::NanoId;
+
+    use super::{RateLimit, RateLimiter, RateLimits, MAX_BUCKETS};
+
+    #[test]
+    fn test_ip_limit_does_not_consume_other_buckets() {
+        let limiter = RateLimiter::default();
+        let project: NanoId = "project".to_owned().into();
+        let limits = RateLimits {
+            project: Some(RateLimit {
+                requests: 3,
+                seconds: 60,
+            }),
+            deployment: None,
+            ip: Some(RateLimit {
+                requests: 1,
+                seconds: 60,
+            }),
+        };
+        let first: IpAddr = "1.1.1.1".parse().unwrap();
+        let second: IpAddr = "2.2.2.2".parse().unwrap();
+
+        assert!(limiter.check(&project, &limits, None, Some(first)).is_ok());
+        let retry = limiter.check(&project, &limits, None, Some(first));
+        assert!(retry.unwrap_err().as_secs() > 50);
+        assert!(limiter.check(&project, &limits, None, Some(second)).is_ok());
+
+        let counters = limiter.get_counters(&project);
+        assert_eq!(counters.allowed, 2);
+        assert_eq!(counters.limited, 1);
+    }
+
+    #[test]
+    fn test_buckets_are_bounded() {
+        let limiter = RateLimiter::default();
+        let project: NanoId = "project".to_owned().into();
+        let limits = RateLimits {
+            project: None,
+            deployment: None,
+            ip: Some(RateLimit {
+                requests: 1,
+                seconds: 60,
+            }),
+        };
+        for index in 0..(MAX_BUCKETS as u32 * 2) {
+            let ip = IpAddr::V4(Ipv4Addr::from(index));
+            assert!(limiter.check(&project, &limits, None, Some(ip)).is_ok());
+        }
+        assert!(limiter.state.lock().unwrap().buckets.len() <= MAX_BUCKETS + 1);
+    }
+}
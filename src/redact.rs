@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+/// Shorter values are not redacted, they would show up all over the place by accident
+const MIN_SECRET_LENGTH: usize = 8;
+pub(crate) const REDACTED: &str = "[REDACTED]";
+
+/// Scrubs env values and tokens from logs before they are stored or served
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Redactor {
+    secrets: Arc<Vec<String>>,
+}
+
+impl Redactor {
+    pub(crate) fn new<'a>(values: impl IntoIterator<Item = &'a String>) -> Self {
+        let mut secrets: Vec<String> = values
+            .into_iter()
+            .filter(|value| value.len() >= MIN_SECRET_LENGTH)
+            .cloned()
+            .collect();
+        // longer values first, in case some secret contains another one
+        secrets.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+        secrets.dedup();
+        Self {
+            secrets: Arc::new(secrets),
+        }
+    }
+
+    pub(crate) fn redact(&self, text: &str) -> String {
+        self.secrets.iter().fold(text.to_owned(), |text, secret| {
+            if text.contains(secret.as_str()) {
+                text.replace(secret.as_str(), REDACTED)
+            } else {
+                text
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod redact_tests {
+    use super::Redactor;
+
+    #[test]
+    fn test_redact() {
+        let secrets = ["sk_live_1234", "sk_live_1234_extended", "short"].map(str::to_owned);
+        let redactor = Redactor::new(&secrets);
+        assert_eq!(
+            redactor.redact("key=sk_live_1234_extended other=sk_live_1234 mode=short"),
+            "key=[REDACTED] other=[REDACTED] mode=short"
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use http::{HeaderName, HeaderValue, StatusCode};
+use serde::Deserialize;
+
+/// Redirects, rewrites and custom headers declared in prezel.json
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RoutingRules {
+    pub(crate) redirects: Vec<Redirect>,
+    pub(crate) rewrites: Vec<Rewrite>,
+    pub(crate) headers: Vec<HeaderRule>,
+}
+
+impl RoutingRules {
+    /// returns the status code and location for the first redirect matching the path, if any
+    pub(crate) fn redirect(&self, path: &str, query: Option<&str>) -> Option<(StatusCode, String)> {
+        self.redirects.iter().find_map(|redirect| {
+            let captures = redirect.source.captures(path)?;
+            let location = append_query(
+                redirect.source.fill(&redirect.destination, &captures),
+                query,
+            );
+            Some((redirect.status(), location))
+        })
+    }
+
+    /// returns the path and query the request should be proxied to, if any rewrite matches the path
+    pub(crate) fn rewrite(&self, path: &str, query: Option<&str>) -> Option<String> {
+        self.rewrites.iter().find_map(|rewrite| {
+            let captures = rewrite.source.captures(path)?;
+            Some(append_query(
+                rewrite.source.fill(&rewrite.destination, &captures),
+                query,
+            ))
+        })
+    }
+
+    /// returns all the headers that apply to the path, later rules taking precedence
+    pub(crate) fn headers(&self, path: &str) -> Vec<(String, String)> {
+        let mut headers: Vec<(String, String)> = vec![];
+        let matching = self
+            .headers
+            .iter()
+            .filter(|rule| rule.source.captures(path).is_some());
+        for header in matching.flat_map(|rule| &rule.headers) {
+            headers.retain(|(key, _)| !key.eq_ignore_ascii_case(&header.key));
+            headers.push((header.key.clone(), header.value.clone()));
+        }
+        headers
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) struct Redirect {
+    source: SourcePattern,
+    destination: String,
+    permanent: Option<bool>,
+    status_code: Option<RedirectCode>,
+}
+
+impl Redirect {
+    fn status(&self) -> StatusCode {
+        match (self.status_code, self.permanent) {
+            (Some(RedirectCode(code)), _) => code,
+            (None, Some(true)) => StatusCode::PERMANENT_REDIRECT,
+            (None, _) => StatusCode::TEMPORARY_REDIRECT,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Rewrite {
+    source: SourcePattern,
+    #[serde(deserialize_with = "deserialize_rewrite_destination")]
+    destination: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HeaderRule {
+    source: SourcePattern,
+    headers: Vec<Header>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Header {
+    #[serde(deserialize_with = "deserialize_header_name")]
+    key: String,
+    #[serde(deserialize_with = "deserialize_header_value")]
+    value: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(try_from = "u16")]
+struct RedirectCode(StatusCode);
+
+impl TryFrom<u16> for RedirectCode {
+    type Error = String;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            301 | 302 | 303 | 307 | 308 => Ok(Self(StatusCode::from_u16(value).unwrap())),
+            _ => Err(format!(
+                "invalid redirect status code {value}, expected one of 301, 302, 303, 307 or 308"
+            )),
+        }
+    }
+}
+
+// rewrites are always served by the deployment itself, so they can only point to a path
+fn deserialize_rewrite_destination<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    let destination = String::deserialize(deserializer)?;
+    if destination.starts_with('/') {
+        Ok(destination)
+    } else {
+        Err(serde::de::Error::custom(format!(
+            "invalid rewrite destination {destination}, it should start with /"
+        )))
+    }
+}
+
+// invalid headers would otherwise only fail when inserted into every matching response
+fn deserialize_header_name<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    match HeaderName::from_bytes(name.as_bytes()) {
+        Ok(_) => Ok(name),
+        Err(_) => Err(serde::de::Error::custom(format!(
+            "invalid header name {name}"
+        ))),
+    }
+}
+
+fn deserialize_header_value<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    match HeaderValue::from_str(&value) {
+        Ok(_) => Ok(value),
+        Err(_) => Err(serde::de::Error::custom(format!(
+            "invalid header value {value:?}"
+        ))),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `:name`, matches exactly one segment
+    Param(String),
+    /// `:name*`, matches zero or more segments, only allowed at the end of the pattern
+    Wildcard(String),
+}
+
+/// Path pattern such as `/blog/:slug` or `/docs/:path*`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "String")]
+pub(crate) struct SourcePattern(Vec<Segment>);
+
+impl TryFrom<String> for SourcePattern {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if !value.starts_with('/') {
+            return Err(format!("invalid source {value}, it should start with /"));
+        }
+        let segments: Vec<_> = split_path(&value)
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => match name.strip_suffix('*') {
+                    Some(name) => Segment::Wildcard(name.to_owned()),
+                    None => Segment::Param(name.to_owned()),
+                },
+                None => Segment::Literal(segment.to_owned()),
+            })
+            .collect();
+        let misplaced_wildcard = segments
+            .iter()
+            .rev()
+            .skip(1)
+            .any(|segment| matches!(segment, Segment::Wildcard(_)));
+        let empty_name = segments.iter().any(|segment| {
+            matches!(segment, Segment::Param(name) | Segment::Wildcard(name) if name.is_empty())
+        });
+        if misplaced_wildcard {
+            Err(format!(
+                "invalid source {value}, wildcards are only allowed at the end"
+            ))
+        } else if empty_name {
+            Err(format!("invalid source {value}, parameters need a name"))
+        } else {
+            Ok(Self(segments))
+        }
+    }
+}
+
+impl SourcePattern {
+    fn captures(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut parts = split_path(path);
+        let mut captures = vec![];
+        for segment in &self.0 {
+            match segment {
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => captures.push((name.clone(), parts.next()?.to_owned())),
+                Segment::Wildcard(name) => {
+                    let rest = parts.by_ref().collect::<Vec<_>>().join("/");
+                    captures.push((name.clone(), rest));
+                }
+            }
+        }
+        parts.next().is_none().then_some(captures)
+    }
+
+    fn fill(&self, destination: &str, captures: &[(String, String)]) -> String {
+        // longer names go first so :p does not overwrite the beginning of :path
+        let mut captures = captures.to_vec();
+        captures.sort_by_key(|(name, _)| -(name.len() as i64));
+        captures
+            .iter()
+            .fold(destination.to_owned(), |destination, (name, value)| {
+                destination
+                    .replace(&format!(":{name}*"), value)
+                    .replace(&format!(":{name}"), value)
+            })
+    }
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+fn append_query(destination: String, query: Option<&str>) -> String {
+    match query.filter(|query| !query.is_empty()) {
+        Some(query) if destination.contains('?') => format!("{destination}&{query}"),
+        Some(query) => format!("{destination}?{query}"),
+        None => destination,
+    }
+}
+
+#[cfg(test)]
+mod routing_tests {
+    use http::StatusCode;
+
+    use super::{Header, Redirect, Rewrite, RoutingRules, SourcePattern};
+
+    fn rules(json: &str) -> RoutingRules {
+        #[derive(serde::Deserialize)]
+        struct Rules {
+            #[serde(default)]
+            redirects: Vec<Redirect>,
+            #[serde(default)]
+            rewrites: Vec<Rewrite>,
+        }
+        let Rules {
+            redirects,
+            rewrites,
+        } = serde_json::from_str(json).unwrap();
+        RoutingRules {
+            redirects,
+            rewrites,
+            headers: vec![],
+        }
+    }
+
+    #[test]
+    fn test_redirect_with_wildcard() {
+        let rules = rules(
+            r#"{"redirects": [{"source": "/docs/:path*", "destination": "https://docs.example.com/:path*", "permanent": true}]}"#,
+        );
+        let (code, location) = rules.redirect("/docs/a/b", Some("x=1")).unwrap();
+        assert_eq!(code, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(location, "https://docs.example.com/a/b?x=1");
+        assert!(rules.redirect("/blog/a", None).is_none());
+    }
+
+    #[test]
+    fn test_rewrite_with_param() {
+        let rules =
+            rules(r#"{"rewrites": [{"source": "/user/:id", "destination": "/api/users?id=:id"}]}"#);
+        assert_eq!(rules.rewrite("/user/42", None).unwrap(), "/api/users?id=42");
+        assert!(rules.rewrite("/user/42/posts", None).is_none());
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        assert!(SourcePattern::try_from("/:path*/end".to_owned()).is_err());
+        assert!(SourcePattern::try_from("no-slash".to_owned()).is_err());
+        assert!(serde_json::from_str::<Redirect>(
+            r#"{"source": "/", "destination": "/home", "statusCode": 200}"#
+        )
+        .is_err());
+        assert!(serde_json::from_str::<Header>(r#"{"key": "X Frame", "value": "DENY"}"#).is_err());
+        assert!(serde_json::from_str::<Header>(r#"{"key": "X-Frame", "value": "a\nb"}"#).is_err());
+    }
+}
@@ -73,6 +73,14 @@ impl TlsCertificate {
             false
         }
     }
+
+    /// expiry of the certificate as epoch in seconds
+    pub(crate) fn get_expiry(&self) -> Option<i64> {
+        let cert = read_pem_from_path(Path::new(&self.cert)).ok()?;
+        let epoch = Asn1Time::from_unix(0).ok()?;
+        let diff = epoch.diff(cert.not_after()).ok()?;
+        Some(diff.days as i64 * 24 * 60 * 60 + diff.secs as i64)
+    }
 }
 
 pub(crate) fn write_certificate_to_disk(
@@ -65,6 +65,25 @@ impl CertificateStore {
         self.domains.read().unwrap().get(domain).cloned()
     }
 
+    /// expiry of every ready certificate, epoch in seconds, using "default" for the default one
+    pub(crate) fn get_expiries(&self) -> Vec<(String, i64)> {
+        let default = self.default.read().unwrap().clone();
+        let domains: Vec<_> = self
+            .domains
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|(domain, state)| match state {
+                TlsState::Ready(cert) => Some((domain.clone(), cert.clone())),
+                _ => None,
+            })
+            .collect();
+        std::iter::once(("default".to_owned(), default))
+            .chain(domains)
+            .filter_map(|(domain, cert)| Some((domain, cert.get_expiry()?)))
+            .collect()
+    }
+
     pub(crate) fn has_domain(&self, domain: &str) -> bool {
         self.domains.read().unwrap().contains_key(domain)
     }
@@ -41,6 +41,24 @@ pub(crate) fn decode_token<T: DeserializeOwned>(
     Ok(decoded.claims)
 }
 
+/// Tokens with an audience are rejected by the other decoding functions, as jsonwebtoken refuses
+/// any `aud` it was not told to expect
+pub(crate) fn decode_audience_token<T: DeserializeOwned>(
+    token: &str,
+    secret: &str,
+    audience: &str,
+) -> anyhow::Result<T> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_required_spec_claims(&["exp", "aud"]);
+    validation.set_audience(&[audience]);
+    let decoded = decode::<T>(
+        token,
+        &DecodingKey::from_secret(secret.as_ref()),
+        &validation,
+    )?;
+    Ok(decoded.claims)
+}
+
 // TODO: wrap the above function instead
 pub(crate) fn decode_auth_token(token: &str, secret: &str) -> anyhow::Result<TokenClaims> {
     let result = decode::<TokenClaims>(
@@ -1,8 +1,16 @@
-use std::env;
+use std::{
+    collections::HashMap,
+    env, fmt,
+    sync::{Arc, RwLock},
+};
 
-use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use http::HeaderMap;
+use opentelemetry::{
+    global, propagation::Extractor, trace::TracerProvider as _, Context, KeyValue,
+};
 use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
     runtime,
     trace::{RandomIdGenerator, Sampler, TracerProvider},
     Resource,
@@ -11,9 +19,26 @@ use opentelemetry_semantic_conventions::{
     attribute::{DEPLOYMENT_ENVIRONMENT_NAME, SERVICE_NAME, SERVICE_VERSION},
     SCHEMA_URL,
 };
-use tracing_core::Level;
-use tracing_opentelemetry::OpenTelemetryLayer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::Span;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    layer::SubscriberExt,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::{
+    conf::{LogConf, LogFormat},
+    env::EnvVars,
+};
+
+const COLLECTOR_URL_VAR: &str = "OTEL_COLLECTOR_URL";
+const LOG_FORMAT_VAR: &str = "PREZEL_LOG_FORMAT";
+const DEFAULT_FILTER: &str = "info";
+/// the exporter network stack, its own spans would reenter the OpenTelemetry layer while exporting
+const EXPORTER_TARGETS: [&str; 4] = ["opentelemetry", "tonic", "h2", "hyper"];
 
 // Create a Resource that captures information about the entity for which telemetry is recorded.
 fn resource() -> Resource {
@@ -77,27 +102,49 @@ fn init_tracer_provider(address: &str) -> TracerProvider {
 }
 
 // Initialize tracing-subscriber and return OtelGuard for opentelemetry-related termination processing
-pub(crate) fn init_tracing_subscriber() -> OtelGuard {
-    let partial = tracing_subscriber::registry()
-        // The global level filter prevents the exporter network stack
-        // from reentering the globally installed OpenTelemetryLayer with
-        // its own spans while exporting, as the libraries should not use
-        // tracing levels below DEBUG. If the OpenTelemetry layer needs to
-        // trace spans and events with higher verbosity levels, consider using
-        // per-layer filtering to target the telemetry layer specifically,
-        // e.g. by target matching.
-        .with(tracing_subscriber::filter::LevelFilter::from_level(
-            Level::INFO,
-        ))
-        .with(tracing_subscriber::fmt::layer());
-
-    if let Ok(address) = env::var("OTEL_COLLECTOR_URL") {
+pub(crate) fn init_tracing_subscriber(conf: &LogConf) -> (OtelGuard, LogFilter) {
+    // W3C traceparent and tracestate, used to join the spans of the app containers
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let directives = env::var(EnvFilter::DEFAULT_ENV)
+        .ok()
+        .or(conf.filter.clone())
+        .unwrap_or(DEFAULT_FILTER.to_owned());
+    let filter = EnvFilter::try_new(&directives).unwrap_or_else(|error| {
+        eprintln!("invalid log filter {directives}: {error}");
+        EnvFilter::new(DEFAULT_FILTER)
+    });
+    let (filter, handle) = reload::Layer::new(filter);
+    let log_filter = LogFilter {
+        handle,
+        directives: Arc::new(RwLock::new(directives)),
+    };
+
+    let format = match env::var(LOG_FORMAT_VAR).as_deref() {
+        Ok("json") => LogFormat::Json,
+        Ok("text") => LogFormat::Text,
+        _ => conf.format,
+    };
+    let fmt_layer = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().boxed(),
+    };
+
+    let partial = tracing_subscriber::registry().with(filter).with(fmt_layer);
+
+    let guard = if let Ok(address) = env::var(COLLECTOR_URL_VAR) {
         // address will be normlly: http://jaeger:4317
         let tracer_provider = init_tracer_provider(&address);
         // let meter_provider = init_meter_provider();
         let tracer = tracer_provider.tracer("tracing-otel-subscriber");
+        // The filter can be changed at runtime to anything, e.g. trace, so the exporter
+        // targets are turned off in a per-layer filter that reloads can't override
+        let exporter_filter = EXPORTER_TARGETS.into_iter().fold(
+            Targets::new().with_default(LevelFilter::TRACE),
+            |targets, target| targets.with_target(target, LevelFilter::OFF),
+        );
         partial
-            .with(OpenTelemetryLayer::new(tracer))
+            .with(OpenTelemetryLayer::new(tracer).with_filter(exporter_filter))
             // .with(MetricsLayer::new(meter_provider.clone()))
             .init();
         OtelGuard {
@@ -108,6 +155,73 @@ pub(crate) fn init_tracing_subscriber() -> OtelGuard {
         OtelGuard {
             tracer_provider: None,
         }
+    };
+    (guard, log_filter)
+}
+
+/// Handle to change the log filter of the instance at runtime
+#[derive(Clone)]
+pub(crate) struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    directives: Arc<RwLock<String>>,
+}
+
+impl fmt::Debug for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("LogFilter").field(&self.get()).finish()
+    }
+}
+
+impl LogFilter {
+    pub(crate) fn get(&self) -> String {
+        self.directives.read().unwrap().clone()
+    }
+
+    /// the change is not persisted, a restart goes back to the configured filter
+    pub(crate) fn set(&self, directives: &str) -> anyhow::Result<()> {
+        let filter = EnvFilter::try_new(directives)?;
+        self.handle.reload(filter)?;
+        *self.directives.write().unwrap() = directives.to_owned();
+        Ok(())
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)?.to_str().ok()
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// makes the span a child of the trace context received in the headers, if any
+pub(crate) fn set_parent_from_headers(span: &Span, headers: &HeaderMap) {
+    let context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(context);
+}
+
+/// headers carrying the trace context of the span, empty if traces are not being exported
+pub(crate) fn get_propagation_headers(span: &Span) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    let context: Context = span.context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut headers));
+    headers
+}
+
+/// env variables for the app containers so their spans are sent to the same collector
+pub(crate) fn get_container_otel_env() -> EnvVars {
+    match env::var(COLLECTOR_URL_VAR) {
+        Ok(address) => EnvVars::new(&[
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", address.as_str()),
+            ("OTEL_EXPORTER_OTLP_PROTOCOL", "grpc"),
+            ("OTEL_PROPAGATORS", "tracecontext"),
+        ]),
+        Err(_) => EnvVars::empty(),
     }
 }
 